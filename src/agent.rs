@@ -0,0 +1,348 @@
+//! Agent caching decrypted environments
+//!
+//! `age-env agent` keeps decrypted environments in locked memory for a limited time and
+//! serves them over a Unix socket only accessible to the same user, so passphrase
+//! protected or hardware identities are not needed for every command. Entries are keyed
+//! by the SHA-256 of the ciphertext, so a changed environment is never served stale.
+//!
+//! The protocol is one request line per connection, answered with one line:
+//!
+//! - `GET <digest>`: `OK <base64 plaintext>` or `MISS`
+//! - `PUT <digest> <base64 plaintext>`: `OK`
+//! - `LOCK`: forget every entry, `OK <number forgotten>`
+//! - `STATUS`: `OK <entries> <ttl seconds> <pid>`
+use crate::error::{Error, IoContext, Result};
+use crate::storage::{create_private_dir, current_uid, runtime_dir};
+use base64::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use zeroize::Zeroize;
+
+pub const AGENT_SOCKET_ENV_VAR: &str = "AGE_ENV_AGENT_SOCK";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Socket of the current user's agent: `$AGE_ENV_AGENT_SOCK`, or `agent.sock` in the
+/// runtime directory
+pub fn default_socket_path() -> PathBuf {
+    if let Ok(socket) = env::var(AGENT_SOCKET_ENV_VAR) {
+        return PathBuf::from(socket);
+    }
+    runtime_dir().join("agent.sock")
+}
+
+/// What `agent status` reports
+pub struct AgentStatus {
+    pub entries: usize,
+    pub ttl: Duration,
+    pub pid: u32,
+}
+
+/// Connection details for a running agent
+pub struct AgentClient {
+    socket: PathBuf,
+}
+
+impl AgentClient {
+    /// Client for the agent listening on `socket`, if there is one in a directory only
+    /// the current user can access
+    pub fn connect(socket: impl Into<PathBuf>) -> Option<Self> {
+        let socket = socket.into();
+        if !socket.exists() {
+            return None;
+        }
+        if let Err(err) = create_private_dir(socket_dir(&socket)) {
+            eprintln!(
+                "age-env: warning: Ignoring agent socket {:?}: {}",
+                socket, err
+            );
+            return None;
+        }
+        Some(AgentClient { socket })
+    }
+
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    /// Cached plaintext of `ciphertext`, if the agent has it
+    pub fn get(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let mut response = self.request(&format!("GET {}", digest(ciphertext))).ok()?;
+        let plaintext = response
+            .strip_prefix("OK ")
+            .and_then(|encoded| BASE64_STANDARD.decode(encoded).ok());
+        response.zeroize();
+        plaintext
+    }
+
+    /// Cache `plaintext` as the decryption of `ciphertext`, ignoring failures
+    pub fn put(&self, ciphertext: &[u8], plaintext: &[u8]) {
+        let mut request = format!(
+            "PUT {} {}",
+            digest(ciphertext),
+            BASE64_STANDARD.encode(plaintext)
+        );
+        let _ = self.request(&request);
+        request.zeroize();
+    }
+
+    /// Make the agent forget every environment, returning how many it held
+    pub fn lock(&self) -> Result<usize> {
+        let response = self.request("LOCK")?;
+        response
+            .strip_prefix("OK ")
+            .and_then(|count| count.parse().ok())
+            .ok_or_else(|| self.invalid_response(&response))
+    }
+
+    pub fn status(&self) -> Result<AgentStatus> {
+        let response = self.request("STATUS")?;
+        let fields = response
+            .strip_prefix("OK ")
+            .map(|fields| fields.split(' ').collect::<Vec<&str>>())
+            .unwrap_or_default();
+        match fields[..] {
+            [entries, ttl, pid] => Ok(AgentStatus {
+                entries: entries
+                    .parse()
+                    .map_err(|_| self.invalid_response(&response))?,
+                ttl: Duration::from_secs(
+                    ttl.parse().map_err(|_| self.invalid_response(&response))?,
+                ),
+                pid: pid.parse().map_err(|_| self.invalid_response(&response))?,
+            }),
+            _ => Err(self.invalid_response(&response)),
+        }
+    }
+
+    fn request(&self, request: &str) -> Result<String> {
+        let context = || format!("Failed to talk to the agent on {:?}", self.socket);
+        let mut stream = UnixStream::connect(&self.socket).with_context(context)?;
+        if peer_uid(&stream) != Some(current_uid()) {
+            return Err(Error::InvalidInput(format!(
+                "The agent on {:?} belongs to another user",
+                self.socket
+            )));
+        }
+        stream
+            .set_read_timeout(Some(REQUEST_TIMEOUT))
+            .with_context(context)?;
+        stream
+            .write_all(format!("{}\n", request).as_bytes())
+            .with_context(context)?;
+        let mut response = String::new();
+        BufReader::new(stream)
+            .read_line(&mut response)
+            .with_context(context)?;
+        response.truncate(response.trim_end().len());
+        Ok(response)
+    }
+
+    fn invalid_response(&self, response: &str) -> Error {
+        Error::InvalidInput(format!(
+            "Unexpected response from the agent on {:?}: {:?}",
+            self.socket, response
+        ))
+    }
+}
+
+/// Plaintext kept out of swap while cached and wiped when dropped
+struct LockedBytes(Vec<u8>);
+
+impl LockedBytes {
+    fn new(bytes: Vec<u8>) -> Self {
+        // Best effort: locking fails when RLIMIT_MEMLOCK is too low
+        unsafe {
+            libc::mlock(bytes.as_ptr().cast(), bytes.capacity());
+        }
+        LockedBytes(bytes)
+    }
+}
+
+impl Drop for LockedBytes {
+    fn drop(&mut self) {
+        // Zeroizing empties the vector, so remember what was locked
+        let capacity = self.0.capacity();
+        self.0.zeroize();
+        unsafe {
+            libc::munlock(self.0.as_ptr().cast(), capacity);
+        }
+    }
+}
+
+struct Entry {
+    plaintext: LockedBytes,
+    expires: Instant,
+}
+
+/// Serve decrypted environments on `socket` until killed, keeping each one for `ttl`
+pub fn run_agent(socket: &Path, ttl: Duration) -> Result<()> {
+    harden_process();
+    let listener = bind(socket)?;
+    eprintln!(
+        "age-env agent listening on {:?}, caching environments for {} seconds",
+        socket,
+        ttl.as_secs()
+    );
+
+    let entries: Arc<Mutex<BTreeMap<String, Entry>>> = Arc::default();
+    let expired_entries = Arc::clone(&entries);
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        let now = Instant::now();
+        expired_entries
+            .lock()
+            .unwrap()
+            .retain(|_, entry| entry.expires > now);
+    });
+
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        match peer_uid(&stream) {
+            Some(uid) if uid == current_uid() => {}
+            _ => continue,
+        }
+        let _ = serve(stream, &entries, ttl);
+    }
+    Ok(())
+}
+
+fn serve(
+    stream: UnixStream,
+    entries: &Mutex<BTreeMap<String, Entry>>,
+    ttl: Duration,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+    let mut entries = entries.lock().unwrap();
+    let now = Instant::now();
+    entries.retain(|_, entry| entry.expires > now);
+
+    let mut words = request.trim_end().splitn(3, ' ');
+    let mut response = match (words.next(), words.next(), words.next()) {
+        (Some("GET"), Some(digest), None) => match entries.get(digest) {
+            Some(entry) => format!("OK {}", BASE64_STANDARD.encode(&entry.plaintext.0)),
+            None => "MISS".to_string(),
+        },
+        (Some("PUT"), Some(digest), Some(encoded)) => match BASE64_STANDARD.decode(encoded) {
+            Ok(plaintext) => {
+                entries.insert(
+                    digest.to_string(),
+                    Entry {
+                        plaintext: LockedBytes::new(plaintext),
+                        expires: now + ttl,
+                    },
+                );
+                "OK".to_string()
+            }
+            Err(_) => "ERR invalid base64".to_string(),
+        },
+        (Some("LOCK"), None, None) => {
+            let count = entries.len();
+            entries.clear();
+            format!("OK {}", count)
+        }
+        (Some("STATUS"), None, None) => format!(
+            "OK {} {} {}",
+            entries.len(),
+            ttl.as_secs(),
+            std::process::id()
+        ),
+        _ => "ERR unknown request".to_string(),
+    };
+    drop(entries);
+    request.zeroize();
+    let result = (&stream).write_all(format!("{}\n", response).as_bytes());
+    response.zeroize();
+    result
+}
+
+/// Listen on `socket`, in a directory only the current user can access
+fn bind(socket: &Path) -> Result<UnixListener> {
+    create_private_dir(socket_dir(socket))?;
+
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(Error::InvalidInput(format!(
+                "An agent is already running on {:?}",
+                socket
+            )));
+        }
+        fs::remove_file(socket)
+            .with_context(|| format!("Failed to remove stale agent socket {:?}", socket))?;
+    }
+    let listener =
+        UnixListener::bind(socket).with_context(|| format!("Failed to listen on {:?}", socket))?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to restrict access to {:?}", socket))?;
+    Ok(listener)
+}
+
+fn socket_dir(socket: &Path) -> &Path {
+    socket
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+/// Keep cached plaintext out of core dumps and away from debuggers
+fn harden_process() {
+    unsafe {
+        let no_core = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+        #[cfg(target_os = "linux")]
+        libc::prctl(libc::PR_SET_DUMPABLE, 0);
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> Option<u32> {
+    use std::os::unix::io::AsRawFd;
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut credentials as *mut libc::ucred).cast(),
+            &mut length,
+        )
+    };
+    (result == 0).then_some(credentials.uid)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> Option<u32> {
+    use std::os::unix::io::AsRawFd;
+    let mut uid = 0;
+    let mut gid = 0;
+    let result = unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+    (result == 0).then_some(uid)
+}
+
+fn digest(ciphertext: &[u8]) -> String {
+    Sha256::digest(ciphertext)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
@@ -0,0 +1,246 @@
+//! Encryption and decryption of environment files
+//!
+//! All encryption goes through a `CryptoBackend`. By default environments are encrypted
+//! in-process with the age library. When built with the `age-binary` feature, shelling
+//! out to the `age` or `rage` commands is available as well.
+use crate::error::{Error, IoContext, Result};
+use crate::passphrase::{is_passphrase_encrypted, PromptCallbacks};
+use age::armor::ArmoredReader;
+use age::cli_common::{read_identities, read_recipients, StdinGuard};
+use clap::ValueEnum;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Backend selected with `--backend` / `AGE_ENV_BACKEND`
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Use the age library linked into this binary
+    Native,
+    /// Spawn the external `age` command
+    #[cfg(feature = "age-binary")]
+    Age,
+    /// Spawn the external `rage` command
+    #[cfg(feature = "age-binary")]
+    Rage,
+    /// Store contents unencrypted, for testing command logic without real keys
+    #[cfg(feature = "test-backend")]
+    Test,
+}
+
+impl BackendKind {
+    pub fn build(self) -> Result<Box<dyn CryptoBackend>> {
+        Ok(match self {
+            BackendKind::Native => Box::new(NativeBackend),
+            #[cfg(feature = "age-binary")]
+            BackendKind::Age => Box::new(BinaryBackend::new("age")?),
+            #[cfg(feature = "age-binary")]
+            BackendKind::Rage => Box::new(BinaryBackend::new("rage")?),
+            #[cfg(feature = "test-backend")]
+            BackendKind::Test => Box::new(TestBackend),
+        })
+    }
+}
+
+pub trait CryptoBackend {
+    /// Encrypt `plaintext` so that any of `recipients` can decrypt it
+    fn encrypt(&self, recipients: &[String], plaintext: &[u8]) -> Result<Vec<u8>>;
+
+    /// Decrypt `ciphertext` with the identities stored in `identities_files`
+    fn decrypt(&self, identities_files: &[PathBuf], ciphertext: &[u8]) -> Result<Vec<u8>>;
+
+    /// List the recipients in a recipients file, skipping blank lines and comments
+    fn list_recipients(&self, recipients_file: &Path) -> Result<Vec<String>> {
+        let contents = fs::read_to_string(recipients_file)
+            .with_context(|| format!("Failed to read recipients file {:?}", recipients_file))?;
+        Ok(contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+pub fn encrypt_contents(
+    backend: &dyn CryptoBackend,
+    recipients: &[String],
+    filtered_env_contents_string: String,
+) -> Result<Vec<u8>> {
+    if recipients.is_empty() {
+        return Err(Error::Encrypt("No recipients to encrypt to".to_string()));
+    }
+
+    backend.encrypt(recipients, filtered_env_contents_string.as_bytes())
+}
+
+/// In-process encryption with the age library
+pub struct NativeBackend;
+
+impl CryptoBackend for NativeBackend {
+    fn encrypt(&self, recipients: &[String], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let recipients = read_recipients(
+            recipients.to_vec(),
+            vec![],
+            vec![],
+            None,
+            &mut StdinGuard::new(false),
+        )
+        .map_err(|e| Error::Encrypt(format!("Failed to read recipients: {}", e)))?;
+
+        let encryptor = age::Encryptor::with_recipients(
+            recipients
+                .iter()
+                .map(|recipient| recipient.as_ref() as &dyn age::Recipient),
+        )
+        .map_err(|e| Error::Encrypt(format!("Failed to encrypt: {}", e)))?;
+        let mut encrypted = Vec::new();
+        let mut writer = encryptor
+            .wrap_output(&mut encrypted)
+            .context("Failed to start encryption")?;
+        writer
+            .write_all(plaintext)
+            .context("Failed to encrypt environment contents")?;
+        writer.finish().context("Failed to finish encryption")?;
+        Ok(encrypted)
+    }
+
+    fn decrypt(&self, identities_files: &[PathBuf], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let mut identities: Vec<Box<dyn age::Identity>> = Vec::new();
+        let mut plain_identities_files = Vec::new();
+        for file in identities_files {
+            // Passphrase protected identities files are only unlocked if they are needed
+            match fs::read(file) {
+                Ok(contents) if is_passphrase_encrypted(&contents) => {
+                    let identity = age::encrypted::Identity::from_buffer(
+                        ArmoredReader::new(io::Cursor::new(contents)),
+                        Some(path_to_string(file)?),
+                        PromptCallbacks,
+                        None,
+                    )
+                    .map_err(|e| Error::Decrypt(format!("Failed to read identities: {}", e)))?
+                    .expect("Passphrase encrypted identities file");
+                    identities.push(Box::new(identity));
+                }
+                _ => plain_identities_files.push(path_to_string(file)?),
+            }
+        }
+        if !plain_identities_files.is_empty() {
+            identities.extend(
+                read_identities(plain_identities_files, None, &mut StdinGuard::new(false))
+                    .map_err(|e| Error::Decrypt(format!("Failed to read identities: {}", e)))?,
+            );
+        }
+
+        let decryptor = age::Decryptor::new_buffered(ciphertext)
+            .map_err(|e| Error::Decrypt(format!("Failed to parse environment file: {}", e)))?;
+        let mut reader = decryptor
+            .decrypt(identities.iter().map(|identity| identity.as_ref()))
+            .map_err(|e| Error::Decrypt(format!("Failed to decrypt environment: {}", e)))?;
+        let mut contents = Vec::new();
+        reader
+            .read_to_end(&mut contents)
+            .map_err(|e| Error::Decrypt(format!("Failed to decrypt environment: {}", e)))?;
+        Ok(contents)
+    }
+}
+
+fn path_to_string(path: &Path) -> Result<String> {
+    path.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| Error::InvalidInput(format!("Path {:?} is not valid UTF-8", path)))
+}
+
+/// Shells out to an age-compatible command such as `age` or `rage`
+#[cfg(feature = "age-binary")]
+pub struct BinaryBackend {
+    program: String,
+}
+
+#[cfg(feature = "age-binary")]
+impl BinaryBackend {
+    pub fn new(program: &str) -> Result<Self> {
+        if which::which(program).is_err() {
+            return Err(Error::BackendUnavailable(format!(
+                "The '{}' command is required but it's not installed or not found in the PATH.",
+                program
+            )));
+        }
+        Ok(BinaryBackend {
+            program: program.to_string(),
+        })
+    }
+
+    /// Run `command` with `input` on stdin, wrapping failures with `to_error`
+    fn run(
+        &self,
+        mut command: std::process::Command,
+        input: &[u8],
+        to_error: fn(String) -> Error,
+    ) -> Result<Vec<u8>> {
+        let mut child = command
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to spawn {} command", self.program))?;
+        {
+            let mut stdin = child.stdin.take().expect("stdin is piped");
+            stdin
+                .write_all(input)
+                .with_context(|| format!("Failed to write to {} command", self.program))?;
+        }
+        let output = child
+            .wait_with_output()
+            .with_context(|| format!("Failed to wait for {} command", self.program))?;
+        if !output.status.success() {
+            return Err(to_error(format!(
+                "{} command failed with status {}",
+                self.program, output.status
+            )));
+        }
+        Ok(output.stdout)
+    }
+}
+
+#[cfg(feature = "age-binary")]
+impl CryptoBackend for BinaryBackend {
+    fn encrypt(&self, recipients: &[String], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut command = std::process::Command::new(&self.program);
+        for recipient in recipients {
+            command.arg("-r").arg(recipient);
+        }
+        self.run(command, plaintext, Error::Encrypt)
+    }
+
+    fn decrypt(&self, identities_files: &[PathBuf], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let mut command = std::process::Command::new(&self.program);
+        command.arg("-d");
+        for identities_file in identities_files {
+            command.arg("--identity").arg(identities_file);
+        }
+        self.run(command, ciphertext, Error::Decrypt)
+    }
+}
+
+/// Test double that stores contents unencrypted behind a marker line
+#[cfg(feature = "test-backend")]
+pub struct TestBackend;
+
+#[cfg(feature = "test-backend")]
+const TEST_BACKEND_HEADER: &[u8] = b"age-env-test-backend\n";
+
+#[cfg(feature = "test-backend")]
+impl CryptoBackend for TestBackend {
+    fn encrypt(&self, _recipients: &[String], plaintext: &[u8]) -> Result<Vec<u8>> {
+        Ok([TEST_BACKEND_HEADER, plaintext].concat())
+    }
+
+    fn decrypt(&self, _identities_files: &[PathBuf], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        ciphertext
+            .strip_prefix(TEST_BACKEND_HEADER)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| {
+                Error::Decrypt("Environment was not written by the test backend".to_string())
+            })
+    }
+}
@@ -0,0 +1,77 @@
+//! Key-level comparison of two environments
+//!
+//! Values are only printed when asked for. Even hashes of them would give away short or
+//! guessable values, so otherwise only the keys are shown.
+use crate::dotenv::quote_value;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyChange {
+    Added(String),
+    Removed(String),
+    Changed(String),
+}
+
+/// Keys added, removed or changed going from `old` to `new`, sorted by key
+pub fn diff_envs(old: &BTreeMap<String, String>, new: &BTreeMap<String, String>) -> Vec<KeyChange> {
+    let mut changes = Vec::new();
+    for (key, old_value) in old {
+        match new.get(key) {
+            None => changes.push(KeyChange::Removed(key.clone())),
+            Some(new_value) if new_value != old_value => {
+                changes.push(KeyChange::Changed(key.clone()))
+            }
+            Some(_) => {}
+        }
+    }
+    for key in new.keys() {
+        if !old.contains_key(key) {
+            changes.push(KeyChange::Added(key.clone()));
+        }
+    }
+    changes.sort_by(|a, b| a.key().cmp(b.key()));
+    changes
+}
+
+impl KeyChange {
+    pub fn key(&self) -> &str {
+        match self {
+            KeyChange::Added(key) | KeyChange::Removed(key) | KeyChange::Changed(key) => key,
+        }
+    }
+
+    /// One-line summary like `+ KEY`, without the values
+    pub fn summary(&self) -> String {
+        match self {
+            KeyChange::Added(key) => format!("+ {}", key),
+            KeyChange::Removed(key) => format!("- {}", key),
+            KeyChange::Changed(key) => format!("~ {}", key),
+        }
+    }
+}
+
+/// Describe `change` between `old` and `new`, with the values if `show_values`
+pub fn describe_change(
+    change: &KeyChange,
+    old: &BTreeMap<String, String>,
+    new: &BTreeMap<String, String>,
+    show_values: bool,
+) -> String {
+    if !show_values {
+        return match change {
+            KeyChange::Changed(_) => format!("{} (changed)", change.summary()),
+            _ => change.summary(),
+        };
+    }
+    let key = change.key();
+    match change {
+        KeyChange::Added(_) => format!("{} = {}", change.summary(), quote_value(&new[key])),
+        KeyChange::Removed(_) => format!("{} = {}", change.summary(), quote_value(&old[key])),
+        KeyChange::Changed(_) => format!(
+            "{}: {} -> {}",
+            change.summary(),
+            quote_value(&old[key]),
+            quote_value(&new[key])
+        ),
+    }
+}
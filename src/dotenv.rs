@@ -0,0 +1,461 @@
+//! Reading and writing dotenv contents
+//!
+//! The parser accepts the usual dotenv syntax: comments, `export` prefixes, bare values,
+//! literal single-quoted values and double-quoted values with backslash escapes. Quoted
+//! values may span several lines, so multi-line secrets like PEM keys work.
+//!
+//! Besides keys, an environment may contain directives starting with `@`, like
+//! `@extends=base` or `@interpolate=true`. They are kept in the parsed map so they
+//! survive edits, and are interpreted by `inherit`.
+//!
+//! `serialize_dotenv` double-quotes and escapes every value, so anything parsed here
+//! survives a write and a read byte for byte.
+//!
+//! Stored environments start with a `# age-env-format: 2` line. Older versions wrote
+//! `KEY="value"` without escaping anything, so double-quoted values of environments
+//! without that line are read literally, backslashes included.
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Name of the directive declaring the environment an environment inherits from
+pub const EXTENDS_DIRECTIVE: &str = "@extends";
+
+/// Name of the directive turning on `${...}` references in an environment's values
+pub const INTERPOLATE_DIRECTIVE: &str = "@interpolate";
+
+const DIRECTIVES: &[&str] = &[EXTENDS_DIRECTIVE, INTERPOLATE_DIRECTIVE];
+
+/// First line of stored environments whose double-quoted values use backslash escapes
+pub const FORMAT_HEADER: &str = "# age-env-format: 2";
+
+#[derive(Debug)]
+pub struct ParseError {
+    line: usize,
+    message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn parse_dotenv(source: &str) -> Result<BTreeMap<String, String>, ParseError> {
+    parse(source, true)
+}
+
+/// Parse the decrypted contents of a stored environment, reading environments written
+/// before `FORMAT_HEADER` without escapes
+pub fn parse_stored_env(contents: &str) -> Result<BTreeMap<String, String>, ParseError> {
+    let escapes = contents
+        .lines()
+        .next()
+        .is_some_and(|line| line.trim_end() == FORMAT_HEADER);
+    parse(contents, escapes)
+}
+
+/// `env` as the contents of a stored environment, starting with `FORMAT_HEADER`
+pub fn serialize_stored_env(env: &BTreeMap<String, String>) -> String {
+    format!("{}\n{}", FORMAT_HEADER, serialize_dotenv(env))
+}
+
+fn parse(source: &str, escapes: bool) -> Result<BTreeMap<String, String>, ParseError> {
+    let mut parser = Parser {
+        chars: source.chars().collect(),
+        position: 0,
+        line: 1,
+        escapes,
+    };
+    let mut map = BTreeMap::new();
+    loop {
+        parser.skip_blank_lines_and_comments();
+        if parser.at_end() {
+            break;
+        }
+        let (key, value) = parser.parse_kv()?;
+        map.insert(key, value);
+    }
+    Ok(map)
+}
+
+pub fn serialize_dotenv(env: &BTreeMap<String, String>) -> String {
+    env.iter()
+        .map(|(key, value)| format!("{}={}", key, quote_value(value)))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Whether `key` can be written and read back as a dotenv key
+pub fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Double-quote `value`, escaping everything the parser would otherwise interpret
+pub fn quote_value(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '$' => quoted.push_str("\\$"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+    /// Whether backslashes escape characters in double-quoted values
+    escapes: bool,
+}
+
+impl Parser {
+    fn at_end(&self) -> bool {
+        self.position >= self.chars.len()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        ParseError {
+            line: self.line,
+            message: message.to_string(),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.next();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.next();
+            }
+        }
+    }
+
+    fn skip_blank_lines_and_comments(&mut self) {
+        loop {
+            self.skip_whitespace();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') | Some('\r') => {
+                    self.next();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Expect only whitespace and an optional comment until the end of the line
+    fn finish_line(&mut self) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        self.skip_comment();
+        if self.peek() == Some('\r') {
+            self.next();
+        }
+        match self.next() {
+            None | Some('\n') => Ok(()),
+            Some(c) => Err(self.error(&format!("unexpected character {:?} after value", c))),
+        }
+    }
+
+    fn parse_kv(&mut self) -> Result<(String, String), ParseError> {
+        let mut key = self.parse_key()?;
+        if key == "export" && matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.skip_whitespace();
+            key = self.parse_key()?;
+        }
+        self.skip_whitespace();
+        if self.next() != Some('=') {
+            return Err(self.error(&format!("expected `=` after key {}", key)));
+        }
+        self.skip_whitespace();
+        let value = match self.peek() {
+            Some('"') => self.parse_double_quoted()?,
+            Some('\'') => self.parse_single_quoted()?,
+            _ => self.parse_bare(),
+        };
+        self.finish_line()?;
+        Ok((key, value))
+    }
+
+    fn parse_key(&mut self) -> Result<String, ParseError> {
+        let mut key = String::new();
+        if self.peek() == Some('@') {
+            key.push('@');
+            self.next();
+            while let Some(c) = self.peek() {
+                if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                    key.push(c);
+                    self.next();
+                } else {
+                    break;
+                }
+            }
+            if !DIRECTIVES.contains(&key.as_str()) {
+                return Err(self.error(&format!("unknown directive {}", key)));
+            }
+            return Ok(key);
+        }
+        match self.peek() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+            _ => return Err(self.error("expected a key")),
+        }
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                key.push(c);
+                self.next();
+            } else {
+                break;
+            }
+        }
+        Ok(key)
+    }
+
+    fn parse_bare(&mut self) -> String {
+        let mut value = String::new();
+        while let Some(c) = self.peek() {
+            if matches!(c, ' ' | '\t' | '\n' | '\r' | '#') {
+                break;
+            }
+            value.push(c);
+            self.next();
+        }
+        value
+    }
+
+    fn parse_single_quoted(&mut self) -> Result<String, ParseError> {
+        self.next();
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                None => return Err(self.error("unterminated single-quoted value")),
+                Some('\'') => return Ok(value),
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    fn parse_double_quoted(&mut self) -> Result<String, ParseError> {
+        self.next();
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                None => return Err(self.error("unterminated double-quoted value")),
+                Some('"') => return Ok(value),
+                Some('\\') if self.escapes => match self.next() {
+                    None => return Err(self.error("unterminated double-quoted value")),
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some(c @ ('\\' | '"' | '$')) => value.push(c),
+                    Some(c) => {
+                        value.push('\\');
+                        value.push(c);
+                    }
+                },
+                Some(c) => value.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn env(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn stored_env_starts_with_format_header() {
+        let contents = serialize_stored_env(&env(&[("PATH", "C:\\new\\temp")]));
+        assert!(contents.starts_with(&format!("{}\n", FORMAT_HEADER)));
+        assert_eq!(
+            parse_stored_env(&contents).unwrap(),
+            env(&[("PATH", "C:\\new\\temp")])
+        );
+    }
+
+    #[test]
+    fn stored_env_without_format_header_is_read_literally() {
+        let contents = "PATH=\"C:\\new\\temp\"\nPRICE=\"\\$5\"\nPEM=\"line 1\nline 2\"";
+        assert_eq!(
+            parse_stored_env(contents).unwrap(),
+            env(&[
+                ("PATH", "C:\\new\\temp"),
+                ("PRICE", "\\$5"),
+                ("PEM", "line 1\nline 2"),
+            ])
+        );
+    }
+
+    fn parse(source: &str) -> BTreeMap<String, String> {
+        parse_dotenv(source).unwrap()
+    }
+
+    fn parse_error(source: &str) -> String {
+        parse_dotenv(source).unwrap_err().to_string()
+    }
+
+    proptest! {
+        #[test]
+        fn serialized_env_round_trips(
+            env in prop::collection::btree_map("[A-Za-z_][A-Za-z0-9_]{0,8}", any::<String>(), 0..8)
+        ) {
+            prop_assert_eq!(parse_dotenv(&serialize_dotenv(&env)).unwrap(), env.clone());
+            prop_assert_eq!(parse_stored_env(&serialize_stored_env(&env)).unwrap(), env);
+        }
+
+        #[test]
+        fn serialized_value_round_trips(value in "[\n\r\t \\\\\"'$#=a-zé€😀]*") {
+            let env = env(&[("KEY", &value)]);
+            prop_assert_eq!(parse_dotenv(&serialize_dotenv(&env)).unwrap(), env);
+        }
+    }
+
+    #[test]
+    fn serialized_special_values_round_trip() {
+        for value in [
+            "",
+            "\n",
+            "\nleading newline",
+            "trailing newline\n",
+            "\r\nwindows\r\n",
+            "\ttab\t",
+            "crème brûlée, 東京, 😀",
+            "\\n is not a newline",
+            "${NOT_INTERPOLATED} $HOME",
+            "'single' and \"double\" quotes",
+            " # not a comment ",
+        ] {
+            let env = env(&[("KEY", value)]);
+            assert_eq!(parse(&serialize_dotenv(&env)), env, "value {:?}", value);
+        }
+    }
+
+    #[test]
+    fn parses_bare_and_quoted_values() {
+        assert_eq!(
+            parse("BARE=value\nSINGLE='a \\n $b'\nDOUBLE=\"a\\tb\\n\\\\ \\\" \\$ \\q\"\nEMPTY=\n"),
+            env(&[
+                ("BARE", "value"),
+                ("SINGLE", "a \\n $b"),
+                ("DOUBLE", "a\tb\n\\ \" $ \\q"),
+                ("EMPTY", ""),
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_multi_line_quoted_values() {
+        assert_eq!(
+            parse("PEM=\"-----BEGIN-----\nabc\n-----END-----\"\nNEXT='x\ny'"),
+            env(&[
+                ("PEM", "-----BEGIN-----\nabc\n-----END-----"),
+                ("NEXT", "x\ny")
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_export_prefix() {
+        assert_eq!(
+            parse("export KEY=value\nexport\tOTHER=\"x\"\nexport=plain"),
+            env(&[("KEY", "value"), ("OTHER", "x"), ("export", "plain")])
+        );
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        assert_eq!(
+            parse("# comment\n\n  \t\nKEY=value # trailing\nQUOTED=\"a # b\" # trailing\r\n#END=1"),
+            env(&[("KEY", "value"), ("QUOTED", "a # b")])
+        );
+    }
+
+    #[test]
+    fn allows_spaces_around_equals_and_crlf() {
+        assert_eq!(
+            parse("KEY = value\r\nOTHER=\t'x'\r\n"),
+            env(&[("KEY", "value"), ("OTHER", "x")])
+        );
+    }
+
+    #[test]
+    fn keeps_directives() {
+        assert_eq!(
+            parse("@extends=base\n@interpolate=true\nKEY=value"),
+            env(&[
+                ("@extends", "base"),
+                ("@interpolate", "true"),
+                ("KEY", "value")
+            ])
+        );
+    }
+
+    #[test]
+    fn later_keys_override_earlier_ones() {
+        assert_eq!(parse("KEY=1\nKEY=2"), env(&[("KEY", "2")]));
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        assert_eq!(
+            parse_error("KEY=1\nOTHER"),
+            "line 2: expected `=` after key OTHER"
+        );
+        assert_eq!(parse_error("1KEY=value"), "line 1: expected a key");
+        assert_eq!(
+            parse_error("KEY=a b"),
+            "line 1: unexpected character 'b' after value"
+        );
+        assert_eq!(
+            parse_error("KEY='x'y"),
+            "line 1: unexpected character 'y' after value"
+        );
+        assert_eq!(
+            parse_error("KEY=\"open\nstill open"),
+            "line 2: unterminated double-quoted value"
+        );
+        assert_eq!(
+            parse_error("A=1\nKEY='open"),
+            "line 2: unterminated single-quoted value"
+        );
+        assert_eq!(
+            parse_error("@include=x"),
+            "line 1: unknown directive @include"
+        );
+    }
+}
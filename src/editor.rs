@@ -0,0 +1,119 @@
+//! Editing decrypted environments in `$VISUAL`/`$EDITOR`
+//!
+//! The plaintext only ever lives in a private temp file, created with mode 0600 on a
+//! tmpfs when one is available. The file is overwritten and removed when editing ends,
+//! including when editing is aborted.
+use crate::dotenv::parse_dotenv;
+use crate::error::{Error, IoContext, Result};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Let the user edit `contents`, re-opening the editor until the result parses
+///
+/// Fails with `Error::Aborted` if the user gives up after a parse error.
+pub fn edit_env(contents: &str) -> Result<BTreeMap<String, String>> {
+    let temp_file = TempFile::create(contents)?;
+    loop {
+        run_editor(&temp_file.path)?;
+        let edited =
+            fs::read_to_string(&temp_file.path).context("Failed to read edited environment")?;
+        match parse_dotenv(&edited) {
+            Ok(env) => return Ok(env),
+            Err(error) => {
+                eprintln!("Failed to parse edited environment: {}", error);
+                eprint!("Re-open the editor? [Y/n] ");
+                let mut answer = String::new();
+                let read = io::stdin()
+                    .lock()
+                    .read_line(&mut answer)
+                    .context("Failed to read answer")?;
+                if read == 0 || answer.trim().eq_ignore_ascii_case("n") {
+                    return Err(Error::Aborted(
+                        "Aborted, changes were discarded".to_string(),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+fn run_editor(path: &Path) -> Result<()> {
+    let editor = env::var("VISUAL")
+        .ok()
+        .filter(|editor| !editor.trim().is_empty())
+        .or_else(|| env::var("EDITOR").ok())
+        .filter(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string());
+    let mut words = editor.split_whitespace();
+    let program = words.next().expect("Editor command is not empty");
+    let status = std::process::Command::new(program)
+        .args(words)
+        .arg(path)
+        .status()
+        .with_context(|| format!("Failed to run editor {:?}", editor))?;
+    if !status.success() {
+        return Err(Error::Aborted(format!(
+            "Editor {:?} exited with {}, changes were discarded",
+            editor, status
+        )));
+    }
+    Ok(())
+}
+
+/// Directory for the plaintext temp file, preferring memory-backed ones
+fn temp_dir() -> PathBuf {
+    let candidates = [
+        Some(PathBuf::from("/dev/shm")),
+        env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from),
+    ];
+    candidates
+        .into_iter()
+        .flatten()
+        .find(|dir| dir.is_dir())
+        .unwrap_or_else(env::temp_dir)
+}
+
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn create(contents: &str) -> Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.subsec_nanos())
+            .unwrap_or_default();
+        let path = temp_dir().join(format!("age-env-edit-{}-{}.env", std::process::id(), nanos));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(&path)
+            .context("Failed to create temporary file")?;
+        let temp_file = TempFile { path };
+        file.write_all(contents.as_bytes())
+            .context("Failed to write temporary file")?;
+        Ok(temp_file)
+    }
+}
+
+impl Drop for TempFile {
+    /// Overwrite the plaintext before removing the file
+    fn drop(&mut self) {
+        if let Ok(mut file) = OpenOptions::new().write(true).open(&self.path) {
+            let len = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+            let _ = file.rewind();
+            let _ = io::copy(&mut io::repeat(0).take(len), &mut file);
+            let _ = file.sync_all();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
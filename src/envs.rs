@@ -0,0 +1,157 @@
+//! Environment naming and layout inside the `envs` directory
+//!
+//! Environment names can be path-style, like `team/service/prod`, in which case the
+//! environment is stored in nested folders under `envs/`.
+use crate::error::{Error, IoContext, Result};
+use crate::storage::TEMP_FILE_MARKER;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+const PASSTHROUGH_ENV_PREFIX: &str = "__passthrough_age_env_";
+
+/// Path of the encrypted file for environment `name`
+pub fn env_file(envs_dir: &Path, name: &str) -> Result<PathBuf> {
+    validate_env_name(name)?;
+    Ok(envs_dir.join(name))
+}
+
+/// Check that environment `name` can be stored under `envs_dir`: it can't have the name of
+/// a folder of environments, and none of its folders can be an environment
+pub fn check_env_path(envs_dir: &Path, name: &str) -> Result<()> {
+    let file = env_file(envs_dir, name)?;
+    if file.is_dir() {
+        return Err(Error::InvalidInput(format!(
+            "Environment name {} is already used by a folder of environments",
+            name
+        )));
+    }
+    let mut current = file.parent();
+    while let Some(dir) = current {
+        if dir == envs_dir || !dir.starts_with(envs_dir) {
+            break;
+        }
+        if dir.is_file() {
+            let parent = dir
+                .strip_prefix(envs_dir)
+                .expect("Environment is outside of the envs directory");
+            return Err(Error::InvalidInput(format!(
+                "Environment {} can't be created because {} is an environment",
+                name,
+                parent.display()
+            )));
+        }
+        current = dir.parent();
+    }
+    Ok(())
+}
+
+pub fn validate_env_name(name: &str) -> Result<()> {
+    let path = Path::new(name);
+    let valid = !name.is_empty()
+        && !name.ends_with('/')
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !valid {
+        return Err(Error::InvalidInput(format!(
+            "Invalid environment name {:?}, use names like `prod` or `team/service/prod`",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Names of all environments under `envs_dir`, recursively and sorted
+pub fn list_envs(envs_dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    collect_envs(envs_dir, envs_dir, &mut names)?;
+    names.sort();
+    Ok(names)
+}
+
+fn collect_envs(envs_dir: &Path, dir: &Path, names: &mut Vec<String>) -> Result<()> {
+    for entry in fs::read_dir(dir).context("Failed to read envs directory")? {
+        let path = entry
+            .context("Failed to read file in envs directory")?
+            .path();
+        if path.is_dir() {
+            collect_envs(envs_dir, &path, names)?;
+        } else if !path.to_string_lossy().contains(TEMP_FILE_MARKER) {
+            let name = path
+                .strip_prefix(envs_dir)
+                .expect("Environment is outside of the envs directory")
+                .to_str()
+                .ok_or_else(|| {
+                    Error::InvalidInput(format!("Environment name {:?} is not valid UTF-8", path))
+                })?
+                .to_string();
+            names.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// Remove the now empty folders between `file` and `base_dir`, after `file` was deleted
+pub fn remove_empty_parents(base_dir: &Path, file: &Path) -> Result<()> {
+    let mut current = file.parent();
+    while let Some(dir) = current {
+        if dir == base_dir || !dir.starts_with(base_dir) {
+            break;
+        }
+        let is_empty = fs::read_dir(dir)
+            .map(|mut entries| entries.next().is_none())
+            .unwrap_or(false);
+        if !is_empty {
+            break;
+        }
+        fs::remove_dir(dir).context("Failed to delete empty environment folder")?;
+        current = dir.parent();
+    }
+    Ok(())
+}
+
+/// Render environment names as a tree, one line per folder or environment
+pub fn format_tree(names: &[String]) -> Vec<String> {
+    let split_names = names
+        .iter()
+        .map(|name| name.split('/').collect::<Vec<&str>>())
+        .collect::<Vec<Vec<&str>>>();
+    let mut lines = Vec::new();
+    format_tree_level(&split_names, 0, "", &mut lines);
+    lines
+}
+
+fn format_tree_level(names: &[Vec<&str>], depth: usize, prefix: &str, lines: &mut Vec<String>) {
+    let mut children: Vec<&str> = Vec::new();
+    for name in names {
+        if let Some(component) = name.get(depth) {
+            if children.last() != Some(component) {
+                children.push(component);
+            }
+        }
+    }
+    for (index, child) in children.iter().enumerate() {
+        let is_last = index == children.len() - 1;
+        let (branch, indent) = if is_last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        lines.push(format!("{}{}{}", prefix, branch, child));
+        let below = names
+            .iter()
+            .filter(|name| name.len() > depth + 1 && name[depth] == *child)
+            .cloned()
+            .collect::<Vec<Vec<&str>>>();
+        format_tree_level(&below, depth + 1, &format!("{}{}", prefix, indent), lines);
+    }
+}
+
+/// Environment variable marking `name` as already loaded
+pub fn passthrough_key(name: &str) -> String {
+    let sanitized = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    format!("{}{}", PASSTHROUGH_ENV_PREFIX, sanitized)
+}
@@ -0,0 +1,141 @@
+//! Errors reported by age-env
+//!
+//! Every error class exits with its own code, so scripts can tell them apart:
+//!
+//! | Code | Error                                                        |
+//! |------|--------------------------------------------------------------|
+//! | 1    | I/O and other unexpected failures                            |
+//! | 2    | Invalid input: arguments, environment names, keys            |
+//! | 3    | Environment or version not found                             |
+//! | 4    | Key not found in an environment                              |
+//! | 5    | Decryption failed, e.g. no matching identity                 |
+//! | 6    | Encryption failed, e.g. no or invalid recipients             |
+//! | 7    | Encryption backend unavailable, e.g. `age` is not installed  |
+//! | 8    | Environment contents could not be parsed                     |
+//! | 9    | Aborted by the user                                          |
+//! | 127  | `run-with-env` could not start the command                   |
+//!
+//! `run-with-env` otherwise exits with the code of the command it ran.
+use crate::dotenv::ParseError;
+use std::fmt;
+use std::io;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        context: String,
+        source: io::Error,
+    },
+    InvalidInput(String),
+    EnvNotFound(String),
+    VersionNotFound {
+        name: String,
+        version: u32,
+    },
+    KeyNotFound {
+        key: String,
+        name: String,
+    },
+    Decrypt(String),
+    Encrypt(String),
+    #[cfg_attr(not(feature = "age-binary"), allow(dead_code))]
+    BackendUnavailable(String),
+    Parse {
+        source: String,
+        error: ParseError,
+    },
+    Aborted(String),
+    Spawn {
+        command: String,
+        source: io::Error,
+    },
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io { .. } => 1,
+            Error::InvalidInput(_) => 2,
+            Error::EnvNotFound(_) | Error::VersionNotFound { .. } => 3,
+            Error::KeyNotFound { .. } => 4,
+            Error::Decrypt(_) => 5,
+            Error::Encrypt(_) => 6,
+            Error::BackendUnavailable(_) => 7,
+            Error::Parse { .. } => 8,
+            Error::Aborted(_) => 9,
+            Error::Spawn { .. } => 127,
+        }
+    }
+
+    /// Parse error in the contents read from `source`, like a file name or `stdin`
+    pub fn parse(source: impl Into<String>, error: ParseError) -> Self {
+        Error::Parse {
+            source: source.into(),
+            error,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { context, source } => write!(f, "{}: {}", context, source),
+            Error::InvalidInput(message)
+            | Error::Decrypt(message)
+            | Error::Encrypt(message)
+            | Error::BackendUnavailable(message)
+            | Error::Aborted(message) => write!(f, "{}", message),
+            Error::EnvNotFound(name) => write!(
+                f,
+                "Environment {} does not exist, run `age-env list` to see the available ones",
+                name
+            ),
+            Error::VersionNotFound { name, version } => write!(
+                f,
+                "Version {} of environment {} does not exist, run `age-env history {}` to see the available ones",
+                version, name, name
+            ),
+            Error::KeyNotFound { key, name } => {
+                write!(f, "Key {} not found in environment {}", key, name)
+            }
+            Error::Parse { source, error } => write!(f, "Failed to parse {}: {}", source, error),
+            Error::Spawn { command, source } => {
+                write!(f, "Failed to run command `{}`: {}", command, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } | Error::Spawn { source, .. } => Some(source),
+            Error::Parse { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Attach a description of what was being done to I/O errors
+pub trait IoContext<T> {
+    fn context(self, context: impl Into<String>) -> Result<T>;
+    fn with_context(self, context: impl FnOnce() -> String) -> Result<T>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            context: context.into(),
+            source,
+        })
+    }
+
+    fn with_context(self, context: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            context: context(),
+            source,
+        })
+    }
+}
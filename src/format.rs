@@ -0,0 +1,89 @@
+//! Output formats for `show`
+use crate::dotenv::quote_value;
+use crate::error::{Error, Result};
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// KEY=value lines with values printed as-is
+    Raw,
+    /// KEY="value" lines that can be read back by `create`
+    Dotenv,
+    /// KEY=value lines for `docker run --env-file`
+    Docker,
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl OutputFormat {
+    /// Whether the output is a list of environment assignments, which the passthrough
+    /// marker can be appended to
+    pub fn is_env_file(&self) -> bool {
+        matches!(
+            self,
+            OutputFormat::Raw | OutputFormat::Dotenv | OutputFormat::Docker
+        )
+    }
+}
+
+pub fn format_env(env: &BTreeMap<String, String>, format: OutputFormat) -> Result<String> {
+    Ok(match format {
+        OutputFormat::Json => {
+            if env.is_empty() {
+                return Ok("{}".to_string());
+            }
+            let entries = env
+                .iter()
+                .map(|(key, value)| Ok(format!("  {}", format_line(key, value, format)?)))
+                .collect::<Result<Vec<String>>>()?
+                .join(",\n");
+            format!("{{\n{}\n}}", entries)
+        }
+        OutputFormat::Yaml if env.is_empty() => "{}".to_string(),
+        _ => env
+            .iter()
+            .map(|(key, value)| format_line(key, value, format))
+            .collect::<Result<Vec<String>>>()?
+            .join("\n"),
+    })
+}
+
+fn format_line(key: &str, value: &str, format: OutputFormat) -> Result<String> {
+    Ok(match format {
+        OutputFormat::Raw => format!("{}={}", key, value),
+        OutputFormat::Dotenv => format!("{}={}", key, quote_value(value)),
+        OutputFormat::Docker => {
+            if value.contains('\n') || value.contains('\r') {
+                return Err(Error::InvalidInput(format!(
+                    "Value of {} contains a newline, which docker env files cannot represent",
+                    key
+                )));
+            }
+            format!("{}={}", key, value)
+        }
+        OutputFormat::Yaml => format!("{}: {}", json_string(key), json_string(value)),
+        OutputFormat::Toml => format!("{} = {}", key, json_string(value)),
+        OutputFormat::Json => format!("{}: {}", json_string(key), json_string(value)),
+    })
+}
+
+/// Double-quoted string with JSON escapes, which YAML and TOML accept as well
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
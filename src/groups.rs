@@ -0,0 +1,201 @@
+//! Recipient groups
+//!
+//! A group lists its members in `groups/<name>`, one per line: the name of a named
+//! recipient of the global recipients file, or a public key. Environments refer to it as
+//! `@name` in their recipients, which is expanded to the members' keys when encrypting.
+//! Environments encrypted to a group that lost members are recorded in
+//! `reencrypt-needed` until they are encrypted again.
+use crate::crypto::CryptoBackend;
+use crate::error::{Error, IoContext, Result};
+use crate::recipients::{
+    dedup_recipients, recipient_key, recipient_name, same_recipient, validate_recipient,
+};
+use crate::storage::write_atomic;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const REENCRYPT_NEEDED_FILE: &str = "reencrypt-needed";
+
+pub fn group_file(config_dir: &Path, group: &str) -> Result<PathBuf> {
+    let valid = !group.is_empty()
+        && group
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(Error::InvalidInput(format!(
+            "Invalid group name {:?}, use names like `ops` or `backend-team`",
+            group
+        )));
+    }
+    Ok(config_dir.join("groups").join(group))
+}
+
+/// Names of all groups
+pub fn list_groups(config_dir: &Path) -> Result<Vec<String>> {
+    let dir = config_dir.join("groups");
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut groups = Vec::new();
+    for entry in fs::read_dir(&dir).context("Failed to read groups directory")? {
+        let entry = entry.context("Failed to read file in groups directory")?;
+        if let Some(name) = entry.file_name().to_str() {
+            groups.push(name.to_string());
+        }
+    }
+    groups.sort();
+    Ok(groups)
+}
+
+/// Members of `group`, if it exists
+pub fn read_group(config_dir: &Path, group: &str) -> Result<Option<Vec<String>>> {
+    let file = group_file(config_dir, group)?;
+    if !file.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&file)
+        .with_context(|| format!("Failed to read group file {:?}", file))?;
+    Ok(Some(
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect(),
+    ))
+}
+
+pub fn write_group(config_dir: &Path, group: &str, members: &[String]) -> Result<()> {
+    let file = group_file(config_dir, group)?;
+    fs::create_dir_all(config_dir.join("groups")).context("Failed to create groups directory")?;
+    let contents = members
+        .iter()
+        .map(|member| format!("{}\n", member))
+        .collect::<String>();
+    write_atomic(&file, contents.as_bytes())
+}
+
+fn named_recipients(
+    backend: &dyn CryptoBackend,
+    global_recipients_file: Option<&Path>,
+) -> Result<Vec<String>> {
+    match global_recipients_file {
+        Some(file) => backend.list_recipients(file),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// The public keys `recipients` refer to, expanding `@group`s and the names of named
+/// recipients of `global_recipients_file`. Anything else is left for the backend to
+/// check.
+pub fn resolve_recipient_keys(
+    backend: &dyn CryptoBackend,
+    config_dir: &Path,
+    global_recipients_file: Option<&Path>,
+    recipients: &[String],
+) -> Result<Vec<String>> {
+    let named = named_recipients(backend, global_recipients_file)?;
+    let mut keys = Vec::new();
+    for recipient in recipients {
+        match recipient.strip_prefix('@') {
+            Some(group) => {
+                let members = read_group(config_dir, group)?.ok_or_else(|| {
+                    Error::InvalidInput(format!(
+                        "Unknown group {}, create it with `age-env group {} --add <recipient>`",
+                        recipient, group
+                    ))
+                })?;
+                keys.extend(members.iter().map(|member| resolve_member(&named, member)));
+            }
+            None => keys.push(resolve_member(&named, recipient)),
+        }
+    }
+    Ok(dedup_recipients(keys))
+}
+
+/// The public key of `member`, a key or the name of one of the `named` recipients
+fn resolve_member(named: &[String], member: &str) -> String {
+    named
+        .iter()
+        .find(|recipient| recipient_name(recipient) == Some(member))
+        .map(|recipient| recipient_key(recipient))
+        .unwrap_or(recipient_key(member))
+        .to_string()
+}
+
+/// Check that `member` can join a group: a valid public key or the name of a named
+/// recipient of `global_recipients_file`
+pub fn validate_member(
+    backend: &dyn CryptoBackend,
+    global_recipients_file: Option<&Path>,
+    member: &str,
+) -> Result<()> {
+    if member.starts_with('@') {
+        return Err(Error::InvalidInput(format!(
+            "Group {} can't be a member of another group",
+            member
+        )));
+    }
+    let named = named_recipients(backend, global_recipients_file)?;
+    if named
+        .iter()
+        .any(|recipient| recipient_name(recipient) == Some(member))
+    {
+        return Ok(());
+    }
+    validate_recipient(member).map_err(|_| {
+        Error::InvalidInput(format!(
+            "Unknown recipient {}, add it with `echo '{} <age1...>' | age-env add-recipient` or use a public key",
+            member, member
+        ))
+    })
+}
+
+/// Whether `member` is `entry` of a group, by name or key
+pub fn is_member(entry: &str, member: &str) -> bool {
+    entry == member || recipient_name(entry) == Some(member) || same_recipient(entry, member)
+}
+
+fn reencrypt_needed_file(config_dir: &Path) -> PathBuf {
+    config_dir.join(REENCRYPT_NEEDED_FILE)
+}
+
+/// Environments still encrypted to recipients that were removed from one of their groups
+pub fn envs_needing_reencryption(config_dir: &Path) -> Result<Vec<String>> {
+    let file = reencrypt_needed_file(config_dir);
+    if !file.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = fs::read_to_string(&file).context("Failed to read reencrypt-needed file")?;
+    Ok(contents.lines().map(str::to_string).collect())
+}
+
+pub fn flag_for_reencryption(config_dir: &Path, names: &[String]) -> Result<()> {
+    let mut flagged = envs_needing_reencryption(config_dir)?;
+    flagged.extend(names.iter().cloned());
+    flagged.sort();
+    flagged.dedup();
+    write_reencrypt_needed(config_dir, &flagged)
+}
+
+/// Forget that environment `name` needs re-encryption, after it was encrypted again
+pub fn clear_reencryption_flag(config_dir: &Path, name: &str) -> Result<()> {
+    let mut flagged = envs_needing_reencryption(config_dir)?;
+    if !flagged.iter().any(|flagged| flagged == name) {
+        return Ok(());
+    }
+    flagged.retain(|flagged| flagged != name);
+    write_reencrypt_needed(config_dir, &flagged)
+}
+
+fn write_reencrypt_needed(config_dir: &Path, names: &[String]) -> Result<()> {
+    let file = reencrypt_needed_file(config_dir);
+    if names.is_empty() {
+        return fs::remove_file(&file).context("Failed to delete reencrypt-needed file");
+    }
+    let contents = names
+        .iter()
+        .map(|name| format!("{}\n", name))
+        .collect::<String>();
+    write_atomic(&file, contents.as_bytes())
+}
@@ -0,0 +1,195 @@
+//! Version history of environments
+//!
+//! Every time an environment is written its ciphertext is first copied to
+//! `history/<name>/<version>.age`, and a line with the version, time, author and action
+//! is appended to `history/<name>/log`. Deleting an environment only appends to the log,
+//! so earlier versions stay available to `show --version` and `rollback`.
+//!
+//! The directory of a nested environment is named with its `/`s escaped as `%2F` (and
+//! `%` as `%25`), so `team/prod` is kept in `history/team%2Fprod` and can't collide with
+//! the history of `team`.
+//!
+//! The author is taken from `AGE_ENV_AUTHOR`, falling back to `USER`.
+use crate::envs::validate_env_name;
+use crate::error::{Error, IoContext, Result};
+use crate::storage::write_atomic;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct Version {
+    pub number: u32,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub author: String,
+    pub action: String,
+}
+
+fn history_dir(config_dir: &Path, name: &str) -> Result<PathBuf> {
+    validate_env_name(name)?;
+    Ok(config_dir.join("history").join(encode_name(name)))
+}
+
+fn encode_name(name: &str) -> String {
+    name.replace('%', "%25").replace('/', "%2F")
+}
+
+pub fn version_file(config_dir: &Path, name: &str, number: u32) -> Result<PathBuf> {
+    Ok(history_dir(config_dir, name)?.join(format!("{}.age", number)))
+}
+
+pub fn list_versions(config_dir: &Path, name: &str) -> Result<Vec<Version>> {
+    let log = history_dir(config_dir, name)?.join("log");
+    if !log.exists() {
+        return Ok(Vec::new());
+    }
+    fs::read_to_string(&log)
+        .context("Failed to read history log")?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let invalid = || {
+                Error::InvalidInput(format!("Invalid line in history log {:?}: {:?}", log, line))
+            };
+            let fields = line.splitn(4, '\t').collect::<Vec<&str>>();
+            if fields.len() != 4 {
+                return Err(invalid());
+            }
+            Ok(Version {
+                number: fields[0].parse().map_err(|_| invalid())?,
+                timestamp: fields[1].parse().map_err(|_| invalid())?,
+                author: fields[2].to_string(),
+                action: fields[3].to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Keep the current contents of an environment that predates its history, before it
+/// gets overwritten or deleted
+pub fn snapshot_before_change(config_dir: &Path, env_file: &Path, name: &str) -> Result<()> {
+    if !env_file.is_file() || !list_versions(config_dir, name)?.is_empty() {
+        return Ok(());
+    }
+    let timestamp = fs::metadata(env_file)
+        .and_then(|metadata| metadata.modified())
+        .map(seconds_since_epoch)
+        .unwrap_or_else(|_| seconds_since_epoch(SystemTime::now()));
+    let contents = fs::read(env_file).context("Failed to read environment file")?;
+    append_version(
+        config_dir,
+        name,
+        Some(&contents),
+        timestamp,
+        "unknown",
+        "initial",
+    )
+}
+
+/// Record `ciphertext`, about to be written to environment `name`, as a new version
+pub fn record_version(
+    config_dir: &Path,
+    name: &str,
+    ciphertext: &[u8],
+    action: &str,
+) -> Result<()> {
+    append_version(
+        config_dir,
+        name,
+        Some(ciphertext),
+        seconds_since_epoch(SystemTime::now()),
+        &author(),
+        action,
+    )
+}
+
+pub fn record_deletion(config_dir: &Path, name: &str) -> Result<()> {
+    append_version(
+        config_dir,
+        name,
+        None,
+        seconds_since_epoch(SystemTime::now()),
+        &author(),
+        "delete",
+    )
+}
+
+fn append_version(
+    config_dir: &Path,
+    name: &str,
+    ciphertext: Option<&[u8]>,
+    timestamp: u64,
+    author: &str,
+    action: &str,
+) -> Result<()> {
+    let dir = history_dir(config_dir, name)?;
+    fs::create_dir_all(&dir).context("Failed to create history directory")?;
+    let number = list_versions(config_dir, name)?
+        .last()
+        .map(|version| version.number + 1)
+        .unwrap_or(1);
+    if let Some(ciphertext) = ciphertext {
+        write_atomic(&version_file(config_dir, name, number)?, ciphertext)?;
+    }
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join("log"))
+        .context("Failed to open history log")?;
+    writeln!(
+        log,
+        "{}\t{}\t{}\t{}",
+        number,
+        timestamp,
+        author.replace(['\t', '\n'], " "),
+        action
+    )
+    .context("Failed to write history log")
+}
+
+fn author() -> String {
+    env::var("AGE_ENV_AUTHOR")
+        .or_else(|_| env::var("USER"))
+        .ok()
+        .filter(|author| !author.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Format a Unix timestamp as `YYYY-MM-DD HH:MM:SS UTC`
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+    // Civil date from days since 1970-01-01, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
@@ -0,0 +1,271 @@
+//! Per-environment identity selection
+//!
+//! An environment can list the identity files able to decrypt it in
+//! `env-identities/<name>`, one path per line. Relative paths are resolved against the
+//! config directory and `~/` against the home directory. Environments without a list are
+//! decrypted with the global identities file, which `set-identities-passphrase` can
+//! protect with a passphrase.
+use crate::envs::remove_empty_parents;
+use crate::error::{Error, IoContext, Result};
+use crate::passphrase::{
+    decrypt_with_passphrase, encrypt_with_passphrase, is_passphrase_encrypted, prompt_passphrase,
+};
+use crate::storage::write_atomic;
+use age::secrecy::{ExposeSecret, SecretString};
+use age::x25519;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroize;
+
+pub fn env_identities_file(config_dir: &Path, name: &str) -> PathBuf {
+    config_dir.join("env-identities").join(name)
+}
+
+pub fn read_env_identities(config_dir: &Path, name: &str) -> Result<Option<Vec<String>>> {
+    let file = env_identities_file(config_dir, name);
+    if !file.exists() {
+        return Ok(None);
+    }
+    let contents =
+        fs::read_to_string(&file).context("Failed to read environment identities file")?;
+    Ok(Some(
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect(),
+    ))
+}
+
+pub fn write_env_identities(config_dir: &Path, name: &str, identities: &[String]) -> Result<()> {
+    let file = env_identities_file(config_dir, name);
+    let parent = file
+        .parent()
+        .expect("Identities file has no parent directory");
+    fs::create_dir_all(parent).context("Failed to create env-identities directory")?;
+    let contents = identities
+        .iter()
+        .map(|identity| format!("{}\n", identity))
+        .collect::<String>();
+    write_atomic(&file, contents.as_bytes())
+}
+
+pub fn remove_env_identities(config_dir: &Path, name: &str) -> Result<()> {
+    let file = env_identities_file(config_dir, name);
+    if file.exists() {
+        fs::remove_file(&file).context("Failed to delete environment identities file")?;
+        remove_empty_parents(&config_dir.join("env-identities"), &file)?;
+    }
+    Ok(())
+}
+
+/// Identity files used to decrypt an environment
+pub fn identities_for_env(
+    config_dir: &Path,
+    name: &str,
+    global_identities_file: &Path,
+) -> Result<Vec<PathBuf>> {
+    match read_env_identities(config_dir, name)? {
+        Some(identities) if !identities.is_empty() => identities
+            .iter()
+            .map(|identity| resolve_identity_path(config_dir, identity))
+            .collect(),
+        _ => Ok(vec![global_identities_file.to_path_buf()]),
+    }
+}
+
+pub fn resolve_identity_path(config_dir: &Path, identity: &str) -> Result<PathBuf> {
+    if let Some(rest) = identity.strip_prefix("~/") {
+        let home = env::var("HOME").map_err(|_| {
+            Error::InvalidInput(format!("HOME is not set, cannot resolve {}", identity))
+        })?;
+        return Ok(PathBuf::from(home).join(rest));
+    }
+    Ok(config_dir.join(identity))
+}
+
+/// Plaintext contents of the identities file `file`, with its passphrase if it is
+/// protected with one
+fn read_identities_file(file: &Path) -> Result<(Vec<u8>, Option<SecretString>)> {
+    if !file.exists() {
+        return Ok((Vec::new(), None));
+    }
+    let contents =
+        fs::read(file).with_context(|| format!("Failed to read identities file {:?}", file))?;
+    if !is_passphrase_encrypted(&contents) {
+        return Ok((contents, None));
+    }
+    let passphrase = prompt_passphrase(&format!("Passphrase for identities file {:?}: ", file))?;
+    let plaintext = decrypt_with_passphrase(&passphrase, &contents)?;
+    Ok((plaintext, Some(passphrase)))
+}
+
+/// Public key comment written above every identity, as `age-keygen` does
+const PUBLIC_KEY_COMMENT: &str = "# public key: ";
+
+/// What `add_identities` did
+pub struct AddedIdentities {
+    /// Public keys of the identities that were added
+    pub public_keys: Vec<String>,
+    /// Number of plugin identities that were added, which have no public key
+    pub plugin_identities: usize,
+    /// Number of identities the file already had
+    pub duplicates: usize,
+}
+
+/// An identity line of an identities file
+enum ParsedIdentity {
+    Native(x25519::Identity),
+    /// Held by a plugin, like `AGE-PLUGIN-YUBIKEY-1...`
+    Plugin(age::plugin::Identity),
+}
+
+/// Parse the age and plugin identities in `contents`, read from `source`, skipping blank
+/// lines and comments
+fn parse_identities(source: &str, contents: &str) -> Result<Vec<ParsedIdentity>> {
+    let mut identities = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with("-----BEGIN") {
+            return Err(Error::InvalidInput(format!(
+                "{} holds a PEM key, SSH private keys are used with --ssh-identity instead",
+                source
+            )));
+        }
+        let identity = match line.parse::<x25519::Identity>() {
+            Ok(identity) => ParsedIdentity::Native(identity),
+            Err(_) => ParsedIdentity::Plugin(line.parse::<age::plugin::Identity>().map_err(|_| {
+                Error::InvalidInput(format!(
+                    "Invalid identity on line {} of {}, expected an age secret key (AGE-SECRET-KEY-1...) or a plugin identity (AGE-PLUGIN-...)",
+                    index + 1,
+                    source
+                ))
+            })?),
+        };
+        identities.push(identity);
+    }
+    Ok(identities)
+}
+
+/// Add the age and plugin identities in `identities`, read from `source`, to the identities file
+/// `file`, with their public keys, skipping the ones it already has and keeping its
+/// passphrase protection
+pub fn add_identities(file: &Path, source: &str, identities: &str) -> Result<AddedIdentities> {
+    append_identities(file, parse_identities(source, identities)?, None, None)
+}
+
+/// Generate a new age identity into the identities file `file`, under a `label` comment,
+/// returning its public key. A file without passphrase protection is protected with
+/// `new_passphrase`.
+pub fn generate_identity(
+    file: &Path,
+    label: Option<&str>,
+    new_passphrase: Option<&SecretString>,
+) -> Result<String> {
+    let identity = x25519::Identity::generate();
+    let public_key = identity.to_public().to_string();
+    append_identities(
+        file,
+        vec![ParsedIdentity::Native(identity)],
+        label,
+        new_passphrase,
+    )?;
+    Ok(public_key)
+}
+
+/// Whether the identities file `file` is protected with a passphrase
+pub fn is_identities_file_protected(file: &Path) -> Result<bool> {
+    if !file.exists() {
+        return Ok(false);
+    }
+    let contents =
+        fs::read(file).with_context(|| format!("Failed to read identities file {:?}", file))?;
+    Ok(is_passphrase_encrypted(&contents))
+}
+
+fn append_identities(
+    file: &Path,
+    new_identities: Vec<ParsedIdentity>,
+    label: Option<&str>,
+    new_passphrase: Option<&SecretString>,
+) -> Result<AddedIdentities> {
+    let (mut contents, passphrase) = read_identities_file(file)?;
+    let existing = parse_identities(&format!("{:?}", file), &String::from_utf8_lossy(&contents))?
+        .iter()
+        .map(identity_key)
+        .collect::<Vec<String>>();
+
+    let mut added = AddedIdentities {
+        public_keys: Vec::new(),
+        plugin_identities: 0,
+        duplicates: 0,
+    };
+    let mut added_keys = Vec::new();
+    if !contents.is_empty() && !contents.ends_with(b"\n") {
+        contents.push(b'\n');
+    }
+    for identity in new_identities {
+        let key = identity_key(&identity);
+        if existing.contains(&key) || added_keys.contains(&key) {
+            added.duplicates += 1;
+            continue;
+        }
+        if let Some(label) = label {
+            contents.extend_from_slice(format!("# {}\n", label).as_bytes());
+        }
+        match &identity {
+            ParsedIdentity::Native(native) => {
+                contents.extend_from_slice(
+                    format!(
+                        "{}{}\n{}\n",
+                        PUBLIC_KEY_COMMENT,
+                        key,
+                        native.to_string().expose_secret()
+                    )
+                    .as_bytes(),
+                );
+                added.public_keys.push(key.clone());
+            }
+            ParsedIdentity::Plugin(plugin) => {
+                contents.extend_from_slice(format!("{}\n", plugin).as_bytes());
+                added.plugin_identities += 1;
+            }
+        }
+        added_keys.push(key);
+    }
+    let written = match passphrase.as_ref().or(new_passphrase) {
+        Some(passphrase) => encrypt_with_passphrase(passphrase, &contents)
+            .and_then(|encrypted| write_atomic(file, &encrypted)),
+        None => write_atomic(file, &contents),
+    };
+    contents.zeroize();
+    written?;
+    Ok(added)
+}
+
+/// What tells `identity` apart from others: the public key of an age identity, or a
+/// plugin identity itself
+fn identity_key(identity: &ParsedIdentity) -> String {
+    match identity {
+        ParsedIdentity::Native(native) => native.to_public().to_string(),
+        ParsedIdentity::Plugin(plugin) => plugin.to_string(),
+    }
+}
+
+/// Protect the identities file `file` with `passphrase`, or store it in plain text when
+/// there is none
+pub fn set_identities_passphrase(file: &Path, passphrase: Option<&SecretString>) -> Result<()> {
+    let (mut contents, _) = read_identities_file(file)?;
+    let written = match passphrase {
+        Some(passphrase) => encrypt_with_passphrase(passphrase, &contents)
+            .and_then(|encrypted| write_atomic(file, &encrypted)),
+        None => write_atomic(file, &contents),
+    };
+    contents.zeroize();
+    written
+}
@@ -0,0 +1,75 @@
+//! Environment inheritance
+//!
+//! An environment can declare a parent with an `@extends=<name>` entry. Resolving it
+//! loads the parent first, recursively, and lays the environment's own keys on top.
+//! `@interpolate` is inherited the same way.
+use crate::dotenv::{EXTENDS_DIRECTIVE, INTERPOLATE_DIRECTIVE};
+use crate::error::{Error, Result};
+use std::collections::BTreeMap;
+
+/// An environment with its parents applied
+pub struct ResolvedEnv {
+    pub env: BTreeMap<String, String>,
+    /// Name of the environment each key was taken from
+    pub origins: BTreeMap<String, String>,
+    /// Whether `${...}` references in the values should be expanded
+    pub interpolate: bool,
+}
+
+/// Parent declared by `env`, if any
+pub fn parent(env: &BTreeMap<String, String>) -> Option<&str> {
+    env.get(EXTENDS_DIRECTIVE).map(String::as_str)
+}
+
+/// Resolve environment `name` with contents `env`, loading its parents with `load`
+pub fn resolve_env(
+    name: &str,
+    env: BTreeMap<String, String>,
+    mut load: impl FnMut(&str) -> Result<BTreeMap<String, String>>,
+) -> Result<ResolvedEnv> {
+    let mut chain = vec![(name.to_string(), env)];
+    while let Some(parent_name) = parent(&chain.last().unwrap().1).map(str::to_string) {
+        if chain.iter().any(|(name, _)| *name == parent_name) {
+            let names = chain
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .chain([parent_name.as_str()])
+                .collect::<Vec<&str>>();
+            return Err(Error::InvalidInput(format!(
+                "Environment {} extends itself: {}",
+                parent_name,
+                names.join(" -> ")
+            )));
+        }
+        let parent_env = load(&parent_name)?;
+        chain.push((parent_name, parent_env));
+    }
+
+    let mut resolved = ResolvedEnv {
+        env: BTreeMap::new(),
+        origins: BTreeMap::new(),
+        interpolate: false,
+    };
+    for (name, env) in chain.into_iter().rev() {
+        for (key, value) in env {
+            if key == INTERPOLATE_DIRECTIVE {
+                resolved.interpolate = match value.as_str() {
+                    "true" => true,
+                    "false" => false,
+                    _ => {
+                        return Err(Error::InvalidInput(format!(
+                            "Invalid {} value {:?} in environment {}, use true or false",
+                            INTERPOLATE_DIRECTIVE, value, name
+                        )))
+                    }
+                };
+            }
+            if key.starts_with('@') {
+                continue;
+            }
+            resolved.origins.insert(key.clone(), name.clone());
+            resolved.env.insert(key, value);
+        }
+    }
+    Ok(resolved)
+}
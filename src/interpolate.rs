@@ -0,0 +1,131 @@
+//! Interpolation of references in values
+//!
+//! Environments opt in with `@interpolate=true`. Their values can then refer to other
+//! values with `${KEY}` for a key of the same environment, `${env:NAME}` for a variable
+//! of the process environment and `${age-env:other-env/KEY}` for a key of another
+//! environment. `$${` is a literal `${`.
+use crate::error::{Error, Result};
+use crate::inherit::ResolvedEnv;
+use std::collections::BTreeMap;
+use std::env;
+
+/// Expand the references in the values of `env`, environment `name`, if it opted in,
+/// loading other environments with `load`
+pub fn interpolate_env(
+    name: &str,
+    env: ResolvedEnv,
+    load: impl FnMut(&str) -> Result<ResolvedEnv>,
+) -> Result<BTreeMap<String, String>> {
+    if !env.interpolate {
+        return Ok(env.env);
+    }
+    let keys = env.env.keys().cloned().collect::<Vec<String>>();
+    let mut interpolator = Interpolator {
+        envs: BTreeMap::from([(name.to_string(), env)]),
+        expanded: BTreeMap::new(),
+        stack: Vec::new(),
+        load,
+    };
+    keys.into_iter()
+        .map(|key| {
+            let value = interpolator.expand_key(name, &key)?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+struct Interpolator<F> {
+    envs: BTreeMap<String, ResolvedEnv>,
+    expanded: BTreeMap<(String, String), String>,
+    /// Keys being expanded, to detect cycles
+    stack: Vec<(String, String)>,
+    load: F,
+}
+
+impl<F: FnMut(&str) -> Result<ResolvedEnv>> Interpolator<F> {
+    fn expand_key(&mut self, name: &str, key: &str) -> Result<String> {
+        let id = (name.to_string(), key.to_string());
+        if let Some(value) = self.expanded.get(&id) {
+            return Ok(value.clone());
+        }
+        if let Some(start) = self.stack.iter().position(|entry| *entry == id) {
+            let cycle = self.stack[start..]
+                .iter()
+                .chain([&id])
+                .map(|(name, key)| format!("{}/{}", name, key))
+                .collect::<Vec<String>>();
+            return Err(Error::InvalidInput(format!(
+                "Interpolation cycle: {}",
+                cycle.join(" -> ")
+            )));
+        }
+        if !self.envs.contains_key(name) {
+            let env = (self.load)(name)?;
+            self.envs.insert(name.to_string(), env);
+        }
+        let env = &self.envs[name];
+        let raw = env
+            .env
+            .get(key)
+            .cloned()
+            .ok_or_else(|| Error::KeyNotFound {
+                key: key.to_string(),
+                name: name.to_string(),
+            })?;
+        if !env.interpolate {
+            return Ok(raw);
+        }
+        self.stack.push(id.clone());
+        let value = self.expand_value(name, key, &raw);
+        self.stack.pop();
+        let value = value?;
+        self.expanded.insert(id, value.clone());
+        Ok(value)
+    }
+
+    fn expand_value(&mut self, name: &str, key: &str, raw: &str) -> Result<String> {
+        let mut value = String::with_capacity(raw.len());
+        let mut rest = raw;
+        while let Some(start) = rest.find("${") {
+            if rest[..start].ends_with('$') {
+                value.push_str(&rest[..start - 1]);
+                value.push_str("${");
+                rest = &rest[start + 2..];
+                continue;
+            }
+            value.push_str(&rest[..start]);
+            let Some(end) = rest[start..].find('}') else {
+                return Err(Error::InvalidInput(format!(
+                    "Unterminated `${{` in {} of environment {}, write `$${{` for a literal `${{` or pass --no-interpolate",
+                    key, name
+                )));
+            };
+            let reference = &rest[start + 2..start + end];
+            value.push_str(&self.expand_reference(name, reference)?);
+            rest = &rest[start + end + 1..];
+        }
+        value.push_str(rest);
+        Ok(value)
+    }
+
+    fn expand_reference(&mut self, name: &str, reference: &str) -> Result<String> {
+        if let Some(variable) = reference.strip_prefix("env:") {
+            return env::var(variable).map_err(|_| {
+                Error::InvalidInput(format!(
+                    "Environment variable {} referenced in environment {} is not set",
+                    variable, name
+                ))
+            });
+        }
+        if let Some(target) = reference.strip_prefix("age-env:") {
+            let Some((other, key)) = target.rsplit_once('/') else {
+                return Err(Error::InvalidInput(format!(
+                    "Invalid reference ${{{}}} in environment {}, use ${{age-env:<env>/<KEY>}}",
+                    reference, name
+                )));
+            };
+            return self.expand_key(other, key);
+        }
+        self.expand_key(name, reference)
+    }
+}
@@ -0,0 +1,31 @@
+//! Encrypted environments for the age encryption tool
+//!
+//! This is the library behind the `age-env` command. [`Store`] reads and writes the
+//! environments in a config directory such as `~/.age-env`, so programs can load their
+//! secrets directly instead of running `age-env`.
+#[cfg(unix)]
+pub mod agent;
+pub mod crypto;
+pub mod diff;
+pub mod dotenv;
+pub mod editor;
+pub mod envs;
+pub mod error;
+pub mod format;
+pub mod groups;
+pub mod history;
+pub mod identities;
+pub mod inherit;
+pub mod interpolate;
+pub mod merge;
+pub mod passphrase;
+pub mod preload;
+pub mod recipients;
+pub mod shell;
+pub mod ssh;
+pub mod storage;
+pub mod store;
+
+pub use crypto::{BackendKind, CryptoBackend};
+pub use error::{Error, Result};
+pub use store::Store;
@@ -1,50 +1,97 @@
-use base64::prelude::*;
 /**
  * @name age-env
  * @description A tool for managing encrypted environments for the age encryption tool
 **/
 use clap::Parser;
-use dotenv_parser;
 use std::collections::BTreeMap;
 use std::env;
 use std::fs;
 use std::fs::File;
 use std::io;
+use std::io::IsTerminal;
 use std::io::Read;
-use std::io::Write;
+use std::io::{BufRead, Write};
 use std::path::{Path, PathBuf};
-use std::process::ExitStatus;
+#[cfg(unix)]
+use std::time::Duration;
 
 use clap::CommandFactory;
 use clap_complete::{generate, Shell};
+use zeroize::Zeroize;
 
-const PASSTHROUGH_ENV_PREFIX: &str = "__passthrough_age_env_";
+#[cfg(unix)]
+use age_env::agent::{default_socket_path, run_agent, AgentClient};
+use age_env::crypto::BackendKind;
+use age_env::diff::{describe_change, diff_envs};
+use age_env::dotenv::{is_valid_key, parse_dotenv, serialize_dotenv, INTERPOLATE_DIRECTIVE};
+use age_env::editor::edit_env;
+use age_env::envs::{check_env_path, env_file, format_tree, passthrough_key};
+use age_env::error::{Error, IoContext, Result};
+use age_env::format::{format_env, OutputFormat};
+use age_env::groups::{
+    envs_needing_reencryption, flag_for_reencryption, is_member, list_groups, read_group,
+    validate_member, write_group,
+};
+use age_env::history::{format_timestamp, list_versions, version_file as history_version_file};
+use age_env::identities::{
+    add_identities, generate_identity, is_identities_file_protected, read_env_identities,
+    remove_env_identities, set_identities_passphrase, write_env_identities,
+};
+use age_env::inherit::{resolve_env, ResolvedEnv};
+use age_env::interpolate::interpolate_env;
+use age_env::merge::{merge_envs, OnConflict};
+use age_env::passphrase::prompt_new_passphrase;
+use age_env::preload::{decode_preload, encode_preload, PRELOAD_ENV_VAR};
+use age_env::recipients::{
+    add_recipients, dedup_recipients, explicit_recipients, read_env_recipients,
+    remove_env_recipients, resolve_recipients, same_recipient,
+};
+use age_env::shell::EvalShell;
+use age_env::ssh::{default_ssh_identity, parse_authorized_keys};
+use age_env::Store;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     /// Path to env storage directory
     /// Can be overridden by the AGE_ENV_CONFIG_DIR environment variables
-    #[arg(short = 'd', long, env = "AGE_ENV_CONFIG_DIR", default_value_t = get_config_dir_path().to_str().unwrap().to_string())]
-    config_dir: String,
+    /// [default: the nearest .age-env directory above the current one, or ~/.age-env]
+    #[arg(short = 'd', long, env = "AGE_ENV_CONFIG_DIR")]
+    config_dir: Option<PathBuf>,
     #[arg(long, env = "AGE_ENV_IDENTITIES_FILE")]
     global_identities_file: Option<String>,
     #[arg(long, env = "AGE_ENV_RECIPIENTS_FILE")]
     global_recipients_file: Option<String>,
+    /// SSH private key to decrypt with as well [default: ~/.ssh/id_ed25519 if it exists]
+    #[arg(long, env = "AGE_ENV_SSH_IDENTITY")]
+    ssh_identity: Option<String>,
+    /// Encryption backend to use
+    #[arg(long, env = "AGE_ENV_BACKEND", value_enum, default_value_t = BackendKind::Native)]
+    backend: BackendKind,
+    /// Socket of the agent caching decrypted environments
+    #[cfg(unix)]
+    #[arg(long, env = "AGE_ENV_AGENT_SOCK")]
+    agent_socket: Option<String>,
+    /// Decrypt environments without asking the agent
+    #[cfg(unix)]
+    #[arg(long)]
+    no_agent: bool,
     #[command(subcommand)]
     command: Command,
 }
 
-fn get_config_dir_path() -> PathBuf {
-    if let Ok(config_dir) = env::var("AGE_ENV_CONFIG_DIR") {
-        return PathBuf::from(config_dir);
-    }
-
-    let mut current_dir = env::current_dir().expect("Failed to get current directory");
+/// The config directory used without --config-dir
+fn get_config_dir_path() -> Result<PathBuf> {
+    let mut current_dir = env::current_dir().map_err(|err| {
+        Error::InvalidInput(format!(
+            "Failed to get the current directory ({}), pass --config-dir",
+            err
+        ))
+    })?;
     loop {
         let age_env_path = current_dir.join(".age-env");
         if age_env_path.exists() {
-            return age_env_path;
+            return Ok(age_env_path);
         }
 
         if !current_dir.pop() {
@@ -52,23 +99,74 @@ fn get_config_dir_path() -> PathBuf {
         }
     }
 
-    PathBuf::from(format!("{}/.age-env", env::var("HOME").unwrap()))
+    let home = env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .ok_or_else(|| {
+            Error::InvalidInput(
+                "HOME is not set and there is no .age-env directory above the current one, pass --config-dir".to_string(),
+            )
+        })?;
+    Ok(PathBuf::from(home).join(".age-env"))
 }
 
 #[derive(Parser, Debug)]
 enum Command {
     /// Add a new identity to the global configuration
     #[command(alias = "ai")]
-    AddIdentity,
+    AddIdentity {
+        /// Add the public keys of the new identities to the global recipients without asking
+        #[arg(short = 'y', long, conflicts_with = "no_add_recipient")]
+        add_recipient: bool,
+        /// Don't offer to add the public keys of the new identities to the global recipients
+        #[arg(short = 'n', long)]
+        no_add_recipient: bool,
+    },
     /// Add a new recipient to the global configuration
     #[command(alias = "ar")]
     AddRecipient,
+    /// Add the SSH public keys of an authorized_keys file to the global recipients
+    ImportRecipients {
+        /// authorized_keys file to import
+        #[arg(
+            long,
+            required_unless_present = "github_keys_file",
+            conflicts_with = "github_keys_file"
+        )]
+        authorized_keys: Option<String>,
+        /// Saved copy of https://github.com/<user>.keys to import
+        #[arg(long)]
+        github_keys_file: Option<String>,
+        /// Label for keys without a comment [default: the file name without extension]
+        #[arg(short = 'l', long)]
+        label: Option<String>,
+    },
+    /// Generate a new identity, add its public key to the global recipients and print it
+    Keygen {
+        /// Comment stored above the identity and the recipient
+        #[arg(short = 'l', long)]
+        label: Option<String>,
+        /// Protect the identities file with a passphrase, if it isn't already
+        #[arg(short = 'p', long)]
+        passphrase: bool,
+    },
+    /// Protect the global identities file with a passphrase, or change it
+    SetIdentitiesPassphrase {
+        /// Store the identities file in plain text again
+        #[arg(long)]
+        remove: bool,
+    },
     /// List all environments
     #[command(alias = "l")]
     List {
         /// If active, only show names of environments
         #[arg(short = 's', long)]
         short: bool,
+        /// Show nested environments as a tree
+        #[arg(short = 't', long)]
+        tree: bool,
+        /// Only list environments still encrypted to recipients removed from their groups
+        #[arg(short = 'N', long)]
+        needs_reencrypt: bool,
     },
     ListKeys {
         /// Name of the environment to list keys for
@@ -79,24 +177,33 @@ enum Command {
     Create {
         /// Name of the environment to create
         name: String,
+        /// Read the environment from a dotenv file, relative to the config directory,
+        /// instead of stdin
         #[arg(short = 'f', long)]
         from_env_file: Option<String>,
         #[arg(short = 'r', long)]
         recipient: Option<String>,
         #[arg(short = 'R', long)]
         recipients_file: Option<String>,
+        /// Don't add the global recipients to --recipient/--recipients-file
+        #[arg(short = 'G', long)]
+        no_global_recipients: bool,
         #[arg(short = 'y', long)]
         skip_upsert_confirmation: bool,
         #[arg(short = 'o', long)]
         only: Option<Vec<String>>,
         #[arg(short = 'e', long)]
         exclude: Option<Vec<String>>,
+        /// Encrypt with a passphrase asked on the terminal instead of recipients, to share
+        /// the environment with people without an age key
+        #[arg(short = 'p', long, conflicts_with_all = ["recipient", "recipients_file", "no_global_recipients"])]
+        passphrase: bool,
     },
     /// Show the contents of an environment
     #[command(alias = "s")]
     Show {
-        /// Name of the environment to show
-        name: String,
+        #[command(flatten)]
+        envs: EnvNames,
         #[arg(short = 'o', long)]
         only: Option<Vec<String>>,
         #[arg(short = 'e', long)]
@@ -106,12 +213,18 @@ enum Command {
         /// If environment is already decrypted, pass it through to the command without decrypting it again
         #[arg(short = 'p', long)]
         passthrough: bool,
+        /// Output format
+        #[arg(short = 'F', long, value_enum, default_value_t = OutputFormat::Raw)]
+        format: OutputFormat,
+        /// Show a previous version from the environment's history
+        #[arg(long, conflicts_with_all = ["passthrough", "names"])]
+        version: Option<u32>,
     },
     /// Show the contents of an environment prepared for eval
     #[command(alias = "se")]
     ShowForEval {
-        /// Name of the environment to show
-        name: String,
+        #[command(flatten)]
+        envs: EnvNames,
         #[arg(short = 'o', long)]
         only: Option<Vec<String>>,
         #[arg(short = 'e', long)]
@@ -119,6 +232,9 @@ enum Command {
         /// Preload the environment into an env var, for further use by other commands
         #[arg(short = 'l', long)]
         preload: bool,
+        /// Shell to produce output for, detected from $SHELL by default
+        #[arg(short = 's', long, value_enum)]
+        shell: Option<EvalShell>,
         /// If environment is already decrypted, pass it through to the command without decrypting it again
         #[arg(short = 'p', long)]
         passthrough: bool,
@@ -136,6 +252,7 @@ enum Command {
     #[command(alias = "r")]
     Reset,
     /// Reencrypt an environment with a new set of recipients
+    /// Without --recipient/--recipients-file it keeps its own recipients or the global ones
     #[command(alias = "re")]
     Reencrypt {
         /// Name of the environment to reencrypt
@@ -144,20 +261,68 @@ enum Command {
         recipient: Option<String>,
         #[arg(short = 'R', long)]
         recipients_file: Option<String>,
+        /// Don't add the global recipients to --recipient/--recipients-file
+        #[arg(short = 'G', long)]
+        no_global_recipients: bool,
     },
     /// Reencrypt all environments with a new set of recipients
+    /// Without --recipient/--recipients-file each keeps its own recipients or the global ones
     #[command(alias = "rea")]
     ReencryptAll {
         #[arg(short = 'r', long)]
         recipient: Option<String>,
         #[arg(short = 'R', long)]
         recipients_file: Option<String>,
+        /// Don't add the global recipients to --recipient/--recipients-file
+        #[arg(short = 'G', long)]
+        no_global_recipients: bool,
+    },
+    /// Show or edit the recipients an environment is encrypted to
+    #[command(alias = "rc")]
+    Recipients {
+        /// Name of the environment
+        name: String,
+        /// Add a recipient and reencrypt the environment
+        #[arg(short = 'a', long)]
+        add: Option<Vec<String>>,
+        /// Remove a recipient and reencrypt the environment
+        #[arg(short = 'x', long)]
+        remove: Option<Vec<String>>,
+        /// Forget the stored recipients and reencrypt to the global recipients
+        #[arg(long)]
+        clear: bool,
     },
-    /// Run a command with the environment
+    /// Show or edit the members of a recipient group, or list the groups
+    Group {
+        /// Name of the group, used as @name in recipients
+        name: Option<String>,
+        /// Add a member: a named recipient of the global recipients file, or a public key
+        #[arg(short = 'a', long, requires = "name")]
+        add: Option<Vec<String>>,
+        /// Remove a member, flagging the environments encrypted to the group for re-encryption
+        #[arg(short = 'x', long, requires = "name")]
+        remove: Option<Vec<String>>,
+    },
+    /// Show or edit the identity files used to decrypt an environment
+    #[command(alias = "id")]
+    Identities {
+        /// Name of the environment
+        name: String,
+        /// Add an identity file, relative to the config directory or starting with ~/
+        #[arg(short = 'a', long)]
+        add: Option<Vec<String>>,
+        /// Remove an identity file
+        #[arg(short = 'x', long)]
+        remove: Option<Vec<String>>,
+        /// Forget the environment's identity files and use the global identities file
+        #[arg(long)]
+        clear: bool,
+    },
+    /// Run a command with the environment, `-` reads an environment from stdin
     #[command(alias = "rwe")]
     RunWithEnv {
-        /// Name of the environment to run with
-        name: String,
+        #[command(flatten)]
+        envs: EnvNames,
         #[arg(last = true)]
         command: Vec<String>,
         #[arg(short = 'o', long)]
@@ -171,6 +336,62 @@ enum Command {
         #[arg(short = 'v', long)]
         value: Option<String>,
     },
+    /// Set a single key in an environment, reading the value from stdin or a hidden prompt
+    Set {
+        /// Name of the environment to edit
+        name: String,
+        /// Key to set
+        key: String,
+    },
+    /// Remove keys from an environment
+    Unset {
+        /// Name of the environment to edit
+        name: String,
+        /// Keys to remove
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// Edit an environment in $VISUAL or $EDITOR
+    Edit {
+        /// Name of the environment to edit
+        name: String,
+    },
+    /// Show the keys added, removed or changed between two environments
+    Diff {
+        /// Environment to compare from
+        name: String,
+        /// Environment to compare to
+        #[arg(required_unless_present = "env_file", conflicts_with = "env_file")]
+        other: Option<String>,
+        /// Compare to a plaintext dotenv file instead of another environment, relative to
+        /// the config directory like `create --from-env-file`
+        #[arg(short = 'f', long)]
+        env_file: Option<String>,
+        /// Print the values of the keys that differ
+        #[arg(long)]
+        show_values: bool,
+    },
+    /// List the saved versions of an environment
+    History {
+        /// Name of the environment
+        name: String,
+    },
+    /// Restore a previous version of an environment
+    Rollback {
+        /// Name of the environment to restore
+        name: String,
+        /// Version to restore, as listed by `history`
+        version: u32,
+    },
+    /// Cache decrypted environments in memory, serving them to other commands over a Unix socket
+    #[cfg(unix)]
+    Agent {
+        #[command(subcommand)]
+        command: Option<AgentCommand>,
+        /// Seconds to keep each decrypted environment
+        #[arg(long, default_value_t = 900)]
+        ttl: u64,
+    },
     /// Generate shell completions
     #[command(alias = "g")]
     Generate {
@@ -180,22 +401,175 @@ enum Command {
     },
 }
 
+#[cfg(unix)]
+#[derive(clap::Subcommand, Debug)]
+enum AgentCommand {
+    /// Make the running agent forget every environment
+    Lock,
+    /// Show whether an agent is running and how many environments it holds
+    Status,
+}
+
+/// One or more environments, layered in order with later ones winning
+#[derive(clap::Args, Debug)]
+struct EnvNames {
+    /// Name of the environment
+    #[arg(required_unless_present = "names")]
+    name: Option<String>,
+    /// Layer another environment on top, can be repeated
+    #[arg(short = 'n', long = "name")]
+    names: Vec<String>,
+    /// What to do when environments set the same key to different values
+    #[arg(long, value_enum, default_value_t)]
+    on_conflict: OnConflict,
+    /// Use values literally, even in environments with `@interpolate=true`
+    #[arg(long)]
+    no_interpolate: bool,
+}
+
+impl EnvNames {
+    fn all(&self) -> Vec<String> {
+        self.name.iter().chain(self.names.iter()).cloned().collect()
+    }
+
+    /// Expand references in environment `name`, unless --no-interpolate was passed
+    fn interpolate(
+        &self,
+        store: &Store,
+        name: &str,
+        env: ResolvedEnv,
+    ) -> Result<BTreeMap<String, String>> {
+        if self.no_interpolate {
+            return Ok(env.env);
+        }
+        interpolate_env(name, env, |other| load_layer(store, other))
+    }
+
+    /// Load and interpolate every environment, `-` reads one from stdin
+    fn load(&self, store: &Store) -> Result<Vec<(String, BTreeMap<String, String>)>> {
+        let mut layers = Vec::new();
+        for name in self.all() {
+            let layer = if name == "-" {
+                // Read from stdin
+                let mut stdin_contents = String::new();
+                io::stdin()
+                    .read_to_string(&mut stdin_contents)
+                    .context("Failed to read from stdin")?;
+                let parsed_env =
+                    parse_dotenv(&stdin_contents).map_err(|error| Error::parse("stdin", error))?;
+                resolve_env(&name, parsed_env, |parent| store.load(parent))?
+            } else {
+                load_layer(store, &name)?
+            };
+            let layer = self.interpolate(store, &name, layer)?;
+            layers.push((name, layer));
+        }
+        Ok(layers)
+    }
+}
+
+impl Command {
+    /// Whether the command only reads the config directory, so it can share the lock
+    fn is_read_only(&self) -> bool {
+        match self {
+            Command::List { .. }
+            | Command::ListKeys { .. }
+            | Command::Show { .. }
+            | Command::ShowForEval { .. }
+            | Command::RunWithEnv { .. }
+            | Command::Diff { .. }
+            | Command::History { .. } => true,
+            Command::Recipients {
+                add, remove, clear, ..
+            }
+            | Command::Identities {
+                add, remove, clear, ..
+            } => add.is_none() && remove.is_none() && !clear,
+            Command::Group { add, remove, .. } => add.is_none() && remove.is_none(),
+            _ => false,
+        }
+    }
+
+    /// Whether the command decrypts environments, so it can't run before there is an
+    /// identity to decrypt them with
+    fn needs_identities(&self) -> bool {
+        match self {
+            Command::AddIdentity { .. }
+            | Command::AddRecipient
+            | Command::ImportRecipients { .. }
+            | Command::Keygen { .. }
+            | Command::List { .. }
+            | Command::Create { .. }
+            | Command::Delete { .. }
+            | Command::DeleteAll
+            | Command::Reset
+            | Command::Group { .. }
+            | Command::Identities { .. }
+            | Command::History { .. } => false,
+            Command::Recipients {
+                add, remove, clear, ..
+            } => add.is_some() || remove.is_some() || *clear,
+            _ => true,
+        }
+    }
+}
+
 fn main() {
     let args = Args::parse();
+    if let Err(err) = run(args) {
+        eprintln!("age-env: {}", err);
+        std::process::exit(err.exit_code());
+    }
+}
 
+fn run(args: Args) -> Result<()> {
     if let Command::Generate { shell } = args.command {
         let mut cmd = Args::command();
         let bin_name = cmd.get_name().to_string();
         generate(shell, &mut cmd, bin_name, &mut io::stdout());
-        return;
+        return Ok(());
     }
-
-    if !which::which("age").is_ok() {
-        panic!("The 'age' command is required but it's not installed or not found in the PATH.");
+    #[cfg(unix)]
+    let agent_socket = args
+        .agent_socket
+        .map(PathBuf::from)
+        .unwrap_or_else(default_socket_path);
+    #[cfg(unix)]
+    if let Command::Agent { command, ttl } = args.command {
+        return match command {
+            None => run_agent(&agent_socket, Duration::from_secs(ttl)),
+            Some(command) => {
+                let agent = AgentClient::connect(&agent_socket).ok_or_else(|| {
+                    Error::InvalidInput(format!("No agent is running on {:?}", agent_socket))
+                })?;
+                match command {
+                    AgentCommand::Lock => {
+                        let count = agent.lock()?;
+                        println!("Agent forgot {} environments", count);
+                    }
+                    AgentCommand::Status => {
+                        let status = agent.status()?;
+                        println!(
+                            "Agent {} running on {:?}, holding {} environments for {} seconds each",
+                            status.pid,
+                            agent.socket(),
+                            status.entries,
+                            status.ttl.as_secs()
+                        );
+                    }
+                }
+                Ok(())
+            }
+        };
     }
-    let dir = Path::new(&args.config_dir);
+
+    let config_dir = match args.config_dir {
+        Some(config_dir) => config_dir,
+        None => get_config_dir_path()?,
+    };
+    let dir = config_dir.as_path();
     if !dir.exists() {
-        fs::create_dir(&dir).expect("Failed to create config directory");
+        fs::create_dir(dir).context("Failed to create config directory")?;
     }
     let global_recipients_file_path = args
         .global_recipients_file
@@ -208,59 +582,139 @@ fn main() {
 
     let envs_dir = dir.join("envs");
     if !envs_dir.exists() {
-        fs::create_dir(&envs_dir).expect("Failed to create envs directory");
+        fs::create_dir(&envs_dir).context("Failed to create envs directory")?;
     }
+    let store = Store::open(dir)?
+        .with_backend(args.backend.build()?)
+        .with_identities_file(&identities_file)
+        .with_recipients_file(&global_recipients_file_path);
+    let ssh_identity = args
+        .ssh_identity
+        .map(PathBuf::from)
+        .or_else(default_ssh_identity);
+    let store = match &ssh_identity {
+        Some(ssh_identity) => store.with_ssh_identity(ssh_identity),
+        None => store,
+    };
+    #[cfg(unix)]
+    let store = match AgentClient::connect(&agent_socket) {
+        Some(agent) if !args.no_agent => store.with_agent(agent),
+        _ => store,
+    };
+    let backend = store.backend();
 
-    let valid_pre_init_commands = vec![Command::AddIdentity, Command::AddRecipient];
-
-    #[allow(unused_variables)]
-    let is_pre_init_command = valid_pre_init_commands
-        .into_iter()
-        .any(|command| matches!(&args.command, command));
+    // `edit` only locks once the editor is closed
+    let lock = match args.command {
+        Command::Edit { .. } => None,
+        _ => Some(store.lock(!args.command.is_read_only())?),
+    };
 
-    if !identities_file.exists() && !is_pre_init_command {
-        panic!(
+    if !identities_file.exists() && ssh_identity.is_none() && args.command.needs_identities() {
+        return Err(Error::InvalidInput(format!(
             "Identities file {:?} does not exist. Run `age-env add-identity` to create it.",
             identities_file
-        );
+        )));
     }
 
-    let global_recipients_file_exists = global_recipients_file_path.exists();
-    let global_recipients_file = match global_recipients_file_exists {
-        true => Some(global_recipients_file_path.clone()),
-        false => None,
-    };
+    let global_recipients_file = store.global_recipients_file();
 
     match args.command {
-        Command::AddIdentity => {
-            let mut identities_file = match identities_file.exists() {
-                true => File::options()
-                    .append(true)
-                    .open(&identities_file)
-                    .expect("Failed to open identities file for appending"),
-                false => File::create(&identities_file).expect("Failed to create identities file"),
-            };
-
+        Command::AddIdentity {
+            add_recipient,
+            no_add_recipient,
+        } => {
             println!("Adding identity to file: {:?}", identities_file);
 
             let mut identities = String::new();
             std::io::stdin()
                 .read_to_string(&mut identities)
-                .expect("Failed to read identities from stdin");
-            identities_file
-                .write_all(identities.as_bytes())
-                .expect("Failed to write identities to file");
+                .context("Failed to read identities from stdin")?;
+            let added = add_identities(&identities_file, "stdin", &identities);
+            identities.zeroize();
+            let added = added?;
+            println!(
+                "Added {} identities, {} were already there",
+                added.public_keys.len() + added.plugin_identities,
+                added.duplicates
+            );
+
+            let known = match store.global_recipients_file() {
+                Some(file) => backend.list_recipients(&file)?,
+                None => Vec::new(),
+            };
+            let missing = added
+                .public_keys
+                .into_iter()
+                .filter(|public_key| !known.iter().any(|known| same_recipient(known, public_key)))
+                .collect::<Vec<String>>();
+            if missing.is_empty() || no_add_recipient {
+                return Ok(());
+            }
+            let question = format!(
+                "Add {} to the recipients in {:?}? (y/n)",
+                missing.join(", "),
+                global_recipients_file_path
+            );
+            if add_recipient || ask_on_terminal(&question) {
+                add_recipients(backend, &global_recipients_file_path, &missing, None)?;
+                println!(
+                    "Added {} recipients to {:?}",
+                    missing.len(),
+                    global_recipients_file_path
+                );
+            } else {
+                println!(
+                    "Run `age-env add-recipient` with {} to encrypt new environments to it",
+                    missing.join(", ")
+                );
+            }
         }
-        Command::AddRecipient => {
-            let mut recipients_file = match global_recipients_file_path.exists() {
-                true => File::options()
-                    .append(true)
-                    .open(&global_recipients_file_path)
-                    .expect("Failed to open recipients file for appending"),
-                false => File::create(&global_recipients_file_path)
-                    .expect("Failed to create recipients file"),
+        Command::Keygen { label, passphrase } => {
+            if let Some(label) = &label {
+                if label.trim().is_empty() || label.contains('\n') {
+                    return Err(Error::InvalidInput(
+                        "The label must be a single non-empty line".to_string(),
+                    ));
+                }
+            }
+            let new_passphrase = if passphrase && !is_identities_file_protected(&identities_file)? {
+                Some(prompt_new_passphrase(
+                    "New passphrase for the identities file: ",
+                )?)
+            } else {
+                None
             };
-
+            let public_key =
+                generate_identity(&identities_file, label.as_deref(), new_passphrase.as_ref())?;
+            add_recipients(
+                backend,
+                &global_recipients_file_path,
+                std::slice::from_ref(&public_key),
+                label.as_deref(),
+            )?;
+            eprintln!(
+                "Added the new identity to {:?} and its public key to {:?}",
+                identities_file, global_recipients_file_path
+            );
+            println!("{}", public_key);
+        }
+        Command::SetIdentitiesPassphrase { remove } => {
+            if remove {
+                set_identities_passphrase(&identities_file, None)?;
+                println!(
+                    "Identities file {:?} is now stored in plain text",
+                    identities_file
+                );
+            } else {
+                let passphrase = prompt_new_passphrase("New passphrase for the identities file: ")?;
+                set_identities_passphrase(&identities_file, Some(&passphrase))?;
+                println!(
+                    "Identities file {:?} is now protected with a passphrase",
+                    identities_file
+                );
+            }
+        }
+        Command::AddRecipient => {
             let mut recipients = String::new();
             println!(
                 "Adding recipient to file: {:?}",
@@ -268,35 +722,79 @@ fn main() {
             );
             std::io::stdin()
                 .read_to_string(&mut recipients)
-                .expect("Failed to read recipients from stdin");
-            recipients_file
-                .write_all(recipients.as_bytes())
-                .expect("Failed to write recipients to file");
-        }
-        Command::List { short } => {
-            let files = fs::read_dir(&envs_dir).expect("Failed to read envs directory");
-            for file in files {
-                let mut file = file
-                    .expect("Failed to read file in envs directory")
-                    .path()
-                    .to_str()
-                    .expect("Failed to convert path to string")
-                    .to_string();
+                .context("Failed to read recipients from stdin")?;
+            let recipients = recipients
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string)
+                .collect::<Vec<String>>();
+            let added = add_recipients(backend, &global_recipients_file_path, &recipients, None)?;
+            println!(
+                "Added {} recipients, {} were already there",
+                added.len(),
+                recipients.len() - added.len()
+            );
+        }
+        Command::ImportRecipients {
+            authorized_keys,
+            github_keys_file,
+            label,
+        } => {
+            let file = authorized_keys
+                .or(github_keys_file)
+                .expect("clap requires one of the files");
+            let contents = fs::read_to_string(&file)
+                .with_context(|| format!("Failed to read keys file {:?}", file))?;
+            let label = label.or_else(|| {
+                Path::new(&file)
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .map(str::to_string)
+            });
+            let recipients = parse_authorized_keys(&file, &contents)?
+                .iter()
+                .map(|key| key.to_recipient(label.as_deref()))
+                .collect::<Vec<String>>();
+            let added = add_recipients(backend, &global_recipients_file_path, &recipients, None)?;
+            println!(
+                "Imported {} recipients into {:?}",
+                added.len(),
+                global_recipients_file_path
+            );
+        }
+        Command::List {
+            short,
+            tree,
+            needs_reencrypt,
+        } => {
+            let mut names = store.list()?;
+            if needs_reencrypt {
+                let flagged = envs_needing_reencryption(dir)?;
+                names.retain(|name| flagged.contains(name));
+            }
+            if tree {
+                for line in format_tree(&names) {
+                    println!("{}", line);
+                }
+                return Ok(());
+            }
+            for name in names {
                 if short {
-                    file = file.split("/").last().unwrap().to_string();
+                    println!("{}", name);
+                } else {
+                    println!("{}", envs_dir.join(name).display());
                 }
-                println!("{}", file);
             }
         }
         Command::ListKeys { name } => {
-            let file = envs_dir.join(name.clone());
-            let contents = decrypt_file_contents(&file, &identities_file);
-            let contents_str =
-                String::from_utf8(contents).expect("Failed to convert contents to string");
-            let parsed_env = dotenv_parser::parse_dotenv(&contents_str)
-                .expect("Failed to parse dotenv contents");
-            for key in parsed_env.keys() {
-                println!("{}", key);
+            let resolved = store.resolve(&name)?;
+            for (key, origin) in resolved.origins.iter() {
+                if *origin == name {
+                    println!("{}", key);
+                } else {
+                    println!("{} (from {})", key, origin);
+                }
             }
         }
         Command::Create {
@@ -304,193 +802,214 @@ fn main() {
             from_env_file,
             recipient,
             recipients_file,
+            no_global_recipients,
             skip_upsert_confirmation,
             only,
             exclude,
+            passphrase,
         } => {
-            let file_path = envs_dir.join(name.clone());
+            check_env_path(&envs_dir, &name)?;
+            let file_path = env_file(&envs_dir, &name)?;
             let env_file = from_env_file.map(|file| Path::new(&dir).join(file));
 
-            if file_path.exists() && !skip_upsert_confirmation {
+            if file_path.is_file() && !skip_upsert_confirmation {
                 println!(
                     "Environment {:?} already exists. Do you want to overwrite it? (y/n)",
                     file_path
                 );
+                println!(
+                    "Run `age-env diff {} --env-file <file>` to see which keys would change.",
+                    name
+                );
                 let mut input = String::new();
                 std::io::stdin()
                     .read_line(&mut input)
-                    .expect("Failed to read input from stdin");
-                if input.trim().eq_ignore_ascii_case("y") {
-                    panic!("Aborted");
+                    .context("Failed to read input from stdin")?;
+                if !input.trim().eq_ignore_ascii_case("y") {
+                    return Err(Error::Aborted("Aborted".to_string()));
                 }
             }
 
-            if !global_recipients_file_exists && !recipient.is_some() && !recipients_file.is_some()
-            {
-                panic!(
-                    "Either --recipient or --recipients-file must be provided, or the global recipients file must be present"
-                );
+            let recipients = explicit_recipients(
+                backend,
+                &recipient,
+                &recipients_file,
+                &global_recipients_file,
+                no_global_recipients,
+            )?;
+            let no_recipients = match &recipients {
+                Some(recipients) => recipients.is_empty(),
+                None => store.recipients(&name)?.is_empty(),
+            };
+            if no_recipients && !passphrase {
+                return Err(Error::Encrypt(
+                    "Either --recipient or --recipients-file must be provided, or the global recipients file must be present".to_string()
+                ));
             }
 
             // Read the environment contents from either mode
-            let env_contents = match env_file {
+            let (source, env_contents) = match env_file {
                 Some(file) => {
-                    let mut file = File::open(&file).expect("Failed to open env file");
+                    let mut opened = File::open(&file)
+                        .with_context(|| format!("Failed to open env file {:?}", file))?;
                     let mut contents = String::new();
-                    file.read_to_string(&mut contents)
-                        .expect("Failed to read env file");
-                    contents
+                    opened
+                        .read_to_string(&mut contents)
+                        .with_context(|| format!("Failed to read env file {:?}", file))?;
+                    (format!("{:?}", file), contents)
                 }
                 None => {
                     let mut stdin = String::new();
                     std::io::stdin()
                         .read_to_string(&mut stdin)
-                        .expect("Failed to read env from stdin");
-                    stdin
+                        .context("Failed to read env from stdin")?;
+                    ("stdin".to_string(), stdin)
                 }
             };
-            let parsed_env = dotenv_parser::parse_dotenv(&env_contents)
-                .expect("Failed to parse dotenv contents");
+            let parsed_env =
+                parse_dotenv(&env_contents).map_err(|error| Error::parse(source, error))?;
 
             let filtered_env_contents = apply_only_exclude(parsed_env, &only, &exclude);
-            let filtered_env_contents_string = filtered_env_contents
-                .iter()
-                .map(|(key, value)| format!("{}=\"{}\"", key, value))
-                .collect::<Vec<String>>()
-                .join("\n");
 
-            let status = encrypt_contents_into_file(
-                &recipient,
-                &recipients_file,
-                &global_recipients_file,
-                &file_path,
-                filtered_env_contents_string,
-            );
-            if status.success() {
-                println!("Created environment {} in {:?}", name, file_path);
+            if passphrase {
+                let passphrase = prompt_new_passphrase("Passphrase: ")?;
+                store.save_with_passphrase(&name, &filtered_env_contents, &passphrase, "create")?;
             } else {
-                panic!("Failed to create environment {} in {:?}", name, file_path);
+                store.save_for_recipients(
+                    &name,
+                    &filtered_env_contents,
+                    recipients.as_deref(),
+                    "create",
+                )?;
             }
+            println!("Created environment {} in {:?}", name, file_path);
         }
         Command::Show {
-            name,
+            envs,
             only,
             exclude,
             value,
             passthrough,
+            format,
+            version,
         } => {
-            let file = envs_dir.join(name.clone());
-            if !file.exists() {
-                panic!("Environment {:?} does not exist", file);
+            let names = envs.all();
+            if version.is_none() {
+                check_envs_exist(&store, &names)?;
             }
 
-            let passthrough_key = format!("{}{}", PASSTHROUGH_ENV_PREFIX, name.replace("-", "_"));
             if passthrough {
                 if let Some(key) = value.clone() {
-                    if env::var(key.clone()).is_ok() {
-                        println!("{}", env::var(key).unwrap());
-                        return;
-                    }
-                } else if only.is_some() {
-                    let mut any_miss = false;
-                    for key in only.as_ref().unwrap() {
-                        if env::var(key).is_err() {
-                            any_miss = true;
-                            break;
-                        }
+                    if let Ok(val) = env::var(key) {
+                        println!("{}", val);
+                        return Ok(());
                     }
+                } else if let Some(only_keys) = &only {
+                    let any_miss = only_keys.iter().any(|key| env::var(key).is_err());
                     if !any_miss {
-                        for key in only.as_ref().unwrap() {
-                            println!("{}={}", key, env::var(key).unwrap());
-                        }
-                        return;
+                        let passthrough_env = only_keys
+                            .iter()
+                            .map(|key| (key.clone(), env::var(key).unwrap()))
+                            .collect::<BTreeMap<String, String>>();
+                        print_output(format_env(&passthrough_env, format)?);
+                        return Ok(());
                     }
-                } else if env::var(&passthrough_key).is_ok() {
-                    return;
+                } else if all_passed_through(&names) {
+                    return Ok(());
                 }
             }
-            let preloaded_content = decode_name_from_preload_data(name.clone());
-            let contents = preloaded_content
-                .map(|content| content.into_bytes())
-                .unwrap_or_else(|| decrypt_file_contents(&file, &identities_file));
-            let parsed_env = dotenv_parser::parse_dotenv(
-                &String::from_utf8(contents).expect("Failed to convert bytes to string"),
-            )
-            .expect("Failed to parse dotenv contents");
+            let parsed_env = match version {
+                Some(version) => {
+                    let name = &names[0];
+                    let file = history_version_file(dir, name, version)?;
+                    if !file.exists() {
+                        return Err(Error::VersionNotFound {
+                            name: name.clone(),
+                            version,
+                        });
+                    }
+                    let resolved = resolve_env(name, store.load_file(name, &file)?, |parent| {
+                        store.load(parent)
+                    })?;
+                    envs.interpolate(&store, name, resolved)?
+                }
+                None => merge_envs(envs.load(&store)?, envs.on_conflict)?,
+            };
             let filtered_env_contents = apply_only_exclude(parsed_env, &only, &exclude);
             if let Some(key) = value.clone() {
                 if let Some(val) = filtered_env_contents.get(&key) {
                     println!("{}", val);
                 } else {
-                    panic!("Key {} not found", key);
+                    return Err(Error::KeyNotFound {
+                        key,
+                        name: names.join(", "),
+                    });
                 }
             } else {
-                for (key, value) in filtered_env_contents.iter() {
-                    println!("{}={}", key, value);
-                }
-                if !&exclude.is_some() && !&only.is_some() {
-                    println!("{}={}", passthrough_key, "1");
+                print_output(format_env(&filtered_env_contents, format)?);
+                if exclude.is_none() && only.is_none() && format.is_env_file() {
+                    let markers = names
+                        .iter()
+                        .map(|name| (passthrough_key(name), "1".to_string()))
+                        .collect::<BTreeMap<String, String>>();
+                    print_output(format_env(&markers, format)?);
                 }
             }
         }
         Command::ShowForEval {
-            name,
+            envs,
             only,
             exclude,
             passthrough,
             preload,
+            shell,
         } => {
-            let file = envs_dir.join(name.clone());
-            if !file.exists() {
-                panic!("Environment {:?} does not exist", file);
-            }
-            let passthrough_key = format!("{}{}", PASSTHROUGH_ENV_PREFIX, name.replace("-", "_"));
+            let shell = shell.unwrap_or_else(EvalShell::detect);
+            let names = envs.all();
+            check_envs_exist(&store, &names)?;
             if passthrough {
-                if env::var(&passthrough_key).is_ok() {
-                    return;
-                } else if only.is_some() {
-                    let mut any_miss = false;
-                    for key in only.as_ref().unwrap() {
-                        if env::var(key).is_err() {
-                            any_miss = true;
-                            break;
-                        }
-                    }
+                if all_passed_through(&names) {
+                    return Ok(());
+                } else if let Some(only_keys) = &only {
+                    let any_miss = only_keys.iter().any(|key| env::var(key).is_err());
                     if !any_miss {
-                        for key in only.as_ref().unwrap() {
-                            println!("export {}={}", key, env::var(key).unwrap());
+                        for key in only_keys {
+                            println!("{}", shell.export(key, &env::var(key).unwrap())?);
                         }
-                        return;
+                        return Ok(());
                     }
                 }
             }
-            let preloaded_content = decode_name_from_preload_data(name.clone());
-            let contents = if let Some(content) = preloaded_content {
-                content.into_bytes()
-            } else {
-                decrypt_file_contents(&file, &identities_file)
-            };
-            let parsed_env = dotenv_parser::parse_dotenv(
-                &String::from_utf8(contents).expect("Failed to convert bytes to string"),
-            )
-            .expect("Failed to parse dotenv contents");
-            let filtered_env_contents = apply_only_exclude(parsed_env, &only, &exclude);
             if preload {
-                let new_preload_data = add_contents_to_preload_data(&filtered_env_contents, name);
-                println!("export AGE_ENV_PRELOAD_B64=\"{}\"", new_preload_data);
-                return;
+                let mut new_preload_data = env::var(PRELOAD_ENV_VAR).unwrap_or_default();
+                for name in names.iter() {
+                    let layer = load_layer(&store, name)?;
+                    let mut filtered_layer = apply_only_exclude(layer.env, &only, &exclude);
+                    // References are expanded when the environment is used, not when preloaded
+                    if layer.interpolate {
+                        filtered_layer
+                            .insert(INTERPOLATE_DIRECTIVE.to_string(), "true".to_string());
+                    }
+                    new_preload_data = encode_preload(&new_preload_data, name, &filtered_layer)?;
+                }
+                println!("{}", shell.export(PRELOAD_ENV_VAR, &new_preload_data)?);
+                return Ok(());
             }
+            let parsed_env = merge_envs(envs.load(&store)?, envs.on_conflict)?;
+            let filtered_env_contents = apply_only_exclude(parsed_env, &only, &exclude);
             for (key, value) in filtered_env_contents.iter() {
-                println!("export {}={}", key, value);
+                println!("{}", shell.export(key, value)?);
             }
-            if !&exclude.is_some() && !&only.is_some() {
-                println!("export {}={}", passthrough_key, "1");
+            if exclude.is_none() && only.is_none() {
+                for name in names.iter() {
+                    println!("{}", shell.export(&passthrough_key(name), "1")?);
+                }
             }
         }
         Command::Delete { name } => {
-            let file = envs_dir.join(name.clone());
-            if file.exists() {
-                fs::remove_file(&file).expect("Failed to delete environment file");
+            let file = env_file(&envs_dir, &name)?;
+            if file.is_file() {
+                store.delete(&name)?;
                 println!("Deleted environment {:?}", file);
             } else {
                 println!("Environment {:?} does not exist", file);
@@ -498,21 +1017,18 @@ fn main() {
         }
         Command::DeleteAll => {
             println!("Deleting all environments in {:?}\n", envs_dir);
-            let files = fs::read_dir(&envs_dir)
-                .expect("Failed to read envs directory")
+            let names = store.list()?;
+            let files = names
+                .iter()
+                .map(|name| envs_dir.join(name))
                 .collect::<Vec<_>>();
-            if files.len() == 0 {
+            if files.is_empty() {
                 println!("No environments to delete");
-                return;
+                return Ok(());
             }
             println!("List:");
             for file in files.iter() {
-                println!(
-                    "{:?}",
-                    file.as_ref()
-                        .expect("Failed to read file in envs directory")
-                        .path()
-                );
+                println!("{:?}", file);
             }
             println!(
                 "\nAre you sure you want to delete all files in {:?}? (y/n)",
@@ -521,85 +1037,71 @@ fn main() {
             let mut input = String::new();
             std::io::stdin()
                 .read_line(&mut input)
-                .expect("Failed to read input from stdin");
+                .context("Failed to read input from stdin")?;
             if input.trim().eq_ignore_ascii_case("y") {
-                for file in files.iter() {
-                    let file = file
-                        .as_ref()
-                        .expect("Failed to read file in envs directory")
-                        .path();
-                    if file.is_file() {
-                        fs::remove_file(&file).expect("Failed to delete file");
-                        println!("Deleted file {:?}", file);
+                for (name, file) in names.iter().zip(files.iter()) {
+                    store.delete(name)?;
+                    println!("Deleted file {:?}", file);
+                }
+                for metadata_dir in ["env-recipients", "env-identities"] {
+                    let metadata_dir = dir.join(metadata_dir);
+                    if metadata_dir.exists() {
+                        fs::remove_dir_all(&metadata_dir)
+                            .context("Failed to delete environment metadata directory")?;
                     }
                 }
             } else {
-                panic!("Aborted");
+                return Err(Error::Aborted("Aborted".to_string()));
             }
             println!("Deleted all environments in {:?}", dir);
         }
         Command::RunWithEnv {
-            name,
+            envs,
             command,
             only,
             exclude,
             passthrough,
             value,
         } => {
-            let filtered_env = if name == "-" {
-                // Read from stdin
-                let mut stdin_contents = String::new();
-                io::stdin()
-                    .read_to_string(&mut stdin_contents)
-                    .expect("Failed to read from stdin");
-                let parsed_env = dotenv_parser::parse_dotenv(&stdin_contents)
-                    .expect("Failed to parse dotenv from stdin");
-                apply_only_exclude(parsed_env, &only, &exclude)
-            } else {
-                let file = envs_dir.join(name.clone());
-                if !file.exists() {
-                    panic!("Environment {:?} does not exist", file);
-                }
+            let names = envs.all();
+            let from_stdin = names.iter().any(|name| name == "-");
+            let stored_names = names
+                .iter()
+                .filter(|name| *name != "-")
+                .cloned()
+                .collect::<Vec<String>>();
+            check_envs_exist(&store, &stored_names)?;
 
-                if passthrough {
-                    let passthrough_key =
-                        format!("{}{}", PASSTHROUGH_ENV_PREFIX, name.replace("-", "_"));
-                    if env::var(&passthrough_key).is_ok() {
-                        return;
-                    } else if only.is_some() {
-                        let mut any_miss = false;
-                        for key in only.as_ref().unwrap() {
-                            if env::var(key).is_err() {
-                                any_miss = true;
-                                break;
-                            }
-                        }
-                        if !any_miss {
-                            return;
-                        }
+            if passthrough && !from_stdin {
+                if all_passed_through(&names) {
+                    return Ok(());
+                } else if let Some(only_keys) = &only {
+                    let any_miss = only_keys.iter().any(|key| env::var(key).is_err());
+                    if !any_miss {
+                        return Ok(());
                     }
                 }
-                let preloaded_content = decode_name_from_preload_data(name.clone());
-                let contents = preloaded_content
-                    .map(|content| content.into_bytes())
-                    .unwrap_or_else(|| decrypt_file_contents(&file, &identities_file));
-                let source =
-                    &String::from_utf8(contents).expect("Failed to convert stdout to string");
-                let parsed_env =
-                    dotenv_parser::parse_dotenv(source).expect("Failed to parse dotenv");
-                apply_only_exclude(parsed_env, &only, &exclude)
-            };
+            }
+            let parsed_env = merge_envs(envs.load(&store)?, envs.on_conflict)?;
+            let filtered_env = apply_only_exclude(parsed_env, &only, &exclude);
 
-            if command.len() < 1 {
-                panic!("Command must have at least one argument, pass with -- [command]");
+            if command.is_empty() {
+                return Err(Error::InvalidInput(
+                    "Command must have at least one argument, pass with -- [command]".to_string(),
+                ));
             }
+            // The command may run for a long time, don't keep other processes waiting
+            drop(lock);
             let mut command_process = std::process::Command::new(&command[0]);
 
             if let Some(key) = value {
                 if let Some(val) = filtered_env.get(&key) {
                     command_process.env(key, val);
                 } else {
-                    panic!("Key {} not found in environment", key);
+                    return Err(Error::KeyNotFound {
+                        key,
+                        name: names.join(", "),
+                    });
                 }
             } else {
                 for (key, value) in filtered_env.iter() {
@@ -607,129 +1109,401 @@ fn main() {
                 }
             }
 
-            if name != "-" {
-                command_process.env(
-                    format!("{}{}", PASSTHROUGH_ENV_PREFIX, name.replace("-", "_")),
-                    "1",
-                );
+            for name in stored_names.iter() {
+                command_process.env(passthrough_key(name), "1");
             }
             command_process.args(&command[1..]);
 
-            let mut child = command_process.spawn().expect(&format!(
-                "Failed to spawn command process: `{}`",
-                command[0]
-            ));
-            let status = child.wait().expect("Failed to wait for command process");
+            let mut child = command_process.spawn().map_err(|source| Error::Spawn {
+                command: command[0].clone(),
+                source,
+            })?;
+            let status = child.wait().context("Failed to wait for command process")?;
             std::process::exit(status.code().unwrap_or(1));
         }
         Command::Reset => {
-            fs::remove_dir_all(&dir).expect("Failed to remove config directory");
+            fs::remove_dir_all(dir).context("Failed to remove config directory")?;
         }
         Command::Reencrypt {
             name,
             recipient,
             recipients_file,
+            no_global_recipients,
         } => {
-            let path = envs_dir.join(name.clone());
-            reencrypt(
-                path,
+            let recipients = explicit_recipients(
+                backend,
                 &recipient,
                 &recipients_file,
-                &identities_file,
                 &global_recipients_file,
-            );
+                no_global_recipients,
+            )?;
+            store.reencrypt(&name, recipients.as_deref())?;
         }
         Command::ReencryptAll {
             recipient,
             recipients_file,
+            no_global_recipients,
+        } => {
+            let recipients = explicit_recipients(
+                backend,
+                &recipient,
+                &recipients_file,
+                &global_recipients_file,
+                no_global_recipients,
+            )?;
+            for name in store.list()? {
+                store.reencrypt(&name, recipients.as_deref())?;
+            }
+        }
+        Command::Recipients {
+            name,
+            add,
+            remove,
+            clear,
         } => {
-            for file in fs::read_dir(&envs_dir).expect("Failed to read envs directory") {
-                let file = file.expect("Failed to read file in envs directory");
-                let path = file.path();
-                let name = path
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .expect("Failed to convert path to string");
-                let path = envs_dir.join(name);
-                reencrypt(
-                    path,
-                    &recipient,
-                    &recipients_file,
-                    &identities_file,
+            let file = env_file(&envs_dir, &name)?;
+            if !file.is_file() {
+                return Err(Error::EnvNotFound(name));
+            }
+            let stored_recipients = read_env_recipients(backend, dir, &name)?;
+
+            if add.is_none() && remove.is_none() && !clear {
+                match stored_recipients {
+                    Some(recipients) => {
+                        for recipient in recipients {
+                            println!("{}", recipient);
+                        }
+                    }
+                    None => println!(
+                        "No recipients stored for environment {}, it uses the global recipients file {:?}",
+                        name, global_recipients_file_path
+                    ),
+                }
+                return Ok(());
+            }
+
+            let recipients = if clear {
+                resolve_recipients(backend, None, &None, &None, &global_recipients_file, false)?
+            } else {
+                let mut recipients = resolve_recipients(
+                    backend,
+                    stored_recipients,
+                    &None,
+                    &None,
                     &global_recipients_file,
+                    false,
+                )?;
+                recipients.extend(add.unwrap_or_default());
+                let remove = remove.unwrap_or_default();
+                recipients.retain(|recipient| {
+                    !remove.iter().any(|removed| is_member(recipient, removed))
+                });
+                dedup_recipients(recipients)
+            };
+            if recipients.is_empty() {
+                return Err(Error::Encrypt(format!(
+                    "Environment {} would be left without recipients",
+                    name
+                )));
+            }
+            store.reencrypt(&name, Some(&recipients))?;
+            if clear {
+                remove_env_recipients(dir, &name)?;
+            }
+            println!(
+                "Reencrypted environment {} to {} recipients",
+                name,
+                recipients.len()
+            );
+        }
+        Command::Group { name: None, .. } => {
+            for group in list_groups(dir)? {
+                let members = read_group(dir, &group)?.unwrap_or_default();
+                println!("@{}: {}", group, members.join(", "));
+            }
+        }
+        Command::Group {
+            name: Some(group),
+            add,
+            remove,
+        } => {
+            let group = group.strip_prefix('@').unwrap_or(&group).to_string();
+            let stored_members = read_group(dir, &group)?;
+            if add.is_none() && remove.is_none() {
+                let members = stored_members
+                    .ok_or_else(|| Error::InvalidInput(format!("Unknown group @{}", group)))?;
+                for member in members {
+                    println!("{}", member);
+                }
+                return Ok(());
+            }
+
+            let mut members = stored_members.unwrap_or_default();
+            for member in add.unwrap_or_default() {
+                validate_member(backend, global_recipients_file.as_deref(), &member)?;
+                if !members.iter().any(|existing| is_member(existing, &member)) {
+                    members.push(member);
+                }
+            }
+            let remove = remove.unwrap_or_default();
+            let count = members.len();
+            members.retain(|member| !remove.iter().any(|removed| is_member(member, removed)));
+            let removed = count != members.len();
+            write_group(dir, &group, &members)?;
+            println!("Group @{} has {} members", group, members.len());
+
+            if removed {
+                let reference = format!("@{}", group);
+                let mut affected = Vec::new();
+                for name in store.list()? {
+                    if read_env_recipients(backend, dir, &name)?
+                        .is_some_and(|recipients| recipients.contains(&reference))
+                    {
+                        affected.push(name);
+                    }
+                }
+                if !affected.is_empty() {
+                    flag_for_reencryption(dir, &affected)?;
+                    eprintln!(
+                        "age-env: warning: Removed members can still decrypt {}, encrypted to {}. Run `age-env reencrypt <name>` for each, see `age-env list --needs-reencrypt`",
+                        affected.join(", "),
+                        reference
+                    );
+                }
+            }
+        }
+        Command::Identities {
+            name,
+            add,
+            remove,
+            clear,
+        } => {
+            let file = env_file(&envs_dir, &name)?;
+            if !file.is_file() {
+                return Err(Error::EnvNotFound(name));
+            }
+            let stored_identities = read_env_identities(dir, &name)?;
+
+            if add.is_none() && remove.is_none() && !clear {
+                match stored_identities {
+                    Some(identities) => {
+                        for identity in identities {
+                            println!("{}", identity);
+                        }
+                    }
+                    None => println!(
+                        "No identities stored for environment {}, it uses the global identities file {:?}",
+                        name, identities_file
+                    ),
+                }
+                return Ok(());
+            }
+
+            if clear {
+                remove_env_identities(dir, &name)?;
+                println!("Environment {} now uses the global identities file", name);
+                return Ok(());
+            }
+            let mut identities = stored_identities.unwrap_or_default();
+            for identity in add.unwrap_or_default() {
+                if !identities.contains(&identity) {
+                    identities.push(identity);
+                }
+            }
+            let remove = remove.unwrap_or_default();
+            identities.retain(|identity| !remove.contains(identity));
+            if identities.is_empty() {
+                remove_env_identities(dir, &name)?;
+                println!("Environment {} now uses the global identities file", name);
+            } else {
+                write_env_identities(dir, &name, &identities)?;
+                println!(
+                    "Environment {} is decrypted with {} identity files",
+                    name,
+                    identities.len()
+                );
+            }
+        }
+        Command::Set { name, key } => {
+            if !is_valid_key(&key) {
+                return Err(Error::InvalidInput(format!(
+                    "Invalid key {:?}, keys must start with a letter or underscore and contain only letters, digits and underscores",
+                    key
+                )));
+            }
+            let mut env_contents = store.load(&name)?;
+            let value = if io::stdin().is_terminal() {
+                rpassword::prompt_password(format!("Value for {}: ", key))
+                    .context("Failed to read value from prompt")?
+            } else {
+                let mut value = String::new();
+                io::stdin()
+                    .read_to_string(&mut value)
+                    .context("Failed to read value from stdin")?;
+                let trimmed_len = value
+                    .strip_suffix('\n')
+                    .map(|stripped| stripped.strip_suffix('\r').unwrap_or(stripped).len())
+                    .unwrap_or(value.len());
+                value.truncate(trimmed_len);
+                value
+            };
+            env_contents.insert(key.clone(), value);
+            store.save_with_action(&name, &env_contents, None, "set")?;
+            println!("Set {} in environment {}", key, name);
+        }
+        Command::Unset { name, keys } => {
+            let mut env_contents = store.load(&name)?;
+            let mut removed = 0;
+            for key in keys.iter() {
+                if env_contents.remove(key).is_some() {
+                    removed += 1;
+                } else {
+                    println!("Key {} not found in environment {}", key, name);
+                }
+            }
+            if removed > 0 {
+                store.save_with_action(&name, &env_contents, None, "unset")?;
+                println!("Removed {} keys from environment {}", removed, name);
+            }
+        }
+        Command::Edit { name } => {
+            let file = store.env_file(&name)?;
+            if !file.is_file() {
+                return Err(Error::EnvNotFound(name));
+            }
+            let ciphertext = fs::read(&file).context("Failed to read environment file")?;
+            let env_contents = store.load(&name)?;
+            let edited_contents = edit_env(&(serialize_dotenv(&env_contents) + "\n"))?;
+            let changes = diff_envs(&env_contents, &edited_contents);
+            if changes.is_empty() {
+                println!("No changes to environment {}", name);
+                return Ok(());
+            }
+            for change in changes.iter() {
+                println!("{}", change.summary());
+            }
+            let _lock = store.lock(true)?;
+            if fs::read(&file).ok().as_ref() != Some(&ciphertext) {
+                return Err(Error::Aborted(format!(
+                    "Environment {} changed while it was being edited, changes were discarded",
+                    name
+                )));
+            }
+            store.save_with_action(&name, &edited_contents, None, "edit")?;
+            println!("Updated environment {}", name);
+        }
+        Command::Diff {
+            name,
+            other,
+            env_file,
+            show_values,
+        } => {
+            let env_contents = store.load(&name)?;
+            let other_contents = match (other, env_file) {
+                (Some(other), _) => store.load(&other)?,
+                (None, Some(file)) => {
+                    let file = Path::new(&dir).join(file);
+                    parse_dotenv(
+                        &fs::read_to_string(&file)
+                            .with_context(|| format!("Failed to read env file {:?}", file))?,
+                    )
+                    .map_err(|error| Error::parse(format!("{:?}", file), error))?
+                }
+                (None, None) => unreachable!("clap requires one of them"),
+            };
+            let changes = diff_envs(&env_contents, &other_contents);
+            if changes.is_empty() {
+                println!("No differences");
+            }
+            for change in changes.iter() {
+                println!(
+                    "{}",
+                    describe_change(change, &env_contents, &other_contents, show_values)
+                );
+            }
+        }
+        Command::History { name } => {
+            let versions = list_versions(dir, &name)?;
+            if versions.is_empty() {
+                println!("No history for environment {}", name);
+            }
+            for version in versions {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    version.number,
+                    format_timestamp(version.timestamp),
+                    version.author,
+                    version.action
                 );
             }
         }
+        Command::Rollback { name, version } => {
+            let file = history_version_file(dir, &name, version)?;
+            if !file.exists() {
+                return Err(Error::VersionNotFound { name, version });
+            }
+            let env_contents = store.load_file(&name, &file)?;
+            store.save_with_action(
+                &name,
+                &env_contents,
+                None,
+                &format!("rollback to {}", version),
+            )?;
+            println!("Rolled back environment {} to version {}", name, version);
+        }
         Command::Generate { .. } => {
-            panic!("Generate command is handled above! Should never reach here")
+            unreachable!("Generate command is handled above")
+        }
+        #[cfg(unix)]
+        Command::Agent { .. } => {
+            unreachable!("Agent command is handled above")
         }
     }
+    Ok(())
 }
 
-fn add_contents_to_preload_data(
-    filtered_env_contents: &BTreeMap<String, String>,
-    name: String,
-) -> String {
-    let current_preload = env::var("AGE_ENV_PRELOAD_B64").unwrap_or_default();
-    if current_preload.contains(&format!("{}:", name)) {
-        return current_preload;
+fn check_envs_exist(store: &Store, names: &[String]) -> Result<()> {
+    for name in names {
+        if !store.exists(name)? {
+            return Err(Error::EnvNotFound(name.clone()));
+        }
     }
-    let contents_as_bytes = filtered_env_contents
+    Ok(())
+}
+
+/// Whether all of `names` are already decrypted into the current environment
+fn all_passed_through(names: &[String]) -> bool {
+    names
         .iter()
-        .map(|(key, value)| format!("{}={}", key, value))
-        .collect::<Vec<String>>()
-        .join("\n");
-    let encoded_data = base64::prelude::BASE64_STANDARD.encode(&contents_as_bytes);
-    let new_preload_data = format!(
-        "{}{}:{}",
-        if current_preload.is_empty() {
-            current_preload
-        } else {
-            current_preload + ";"
-        },
-        name,
-        encoded_data
-    );
-    new_preload_data
+        .all(|name| env::var(passthrough_key(name)).is_ok())
 }
 
-fn decode_name_from_preload_data(name: String) -> Option<String> {
-    let full_preload_data = env::var("AGE_ENV_PRELOAD_B64").unwrap_or_default();
-    let preload_data_parts = full_preload_data.split(";").collect::<Vec<&str>>();
-    let for_name_preload_data = preload_data_parts.iter().find(|x| x.starts_with(&name));
-    if for_name_preload_data.is_none() {
-        return None;
+/// Environment `name`, from the preload data if it was preloaded
+fn load_layer(store: &Store, name: &str) -> Result<ResolvedEnv> {
+    match decode_preload(&env::var(PRELOAD_ENV_VAR).unwrap_or_default(), name)? {
+        Some(env) => resolve_env(name, env, |parent| store.load(parent)),
+        None => store.resolve(name),
     }
-    let base64_encoded_data = for_name_preload_data
-        .unwrap()
-        .split(":")
-        .nth(1)
-        .unwrap()
-        .to_string();
-    let decoded_data = base64::prelude::BASE64_STANDARD
-        .decode(&base64_encoded_data)
-        .unwrap();
-    let decoded_data = String::from_utf8(decoded_data).unwrap();
-    Some(decoded_data)
 }
 
-fn reencrypt(
-    path: PathBuf,
-    recipient: &Option<String>,
-    recipients_file: &Option<String>,
-    identities_file: &PathBuf,
-    global_recipients_file: &Option<PathBuf>,
-) -> ExitStatus {
-    let previous_contents = decrypt_file_contents(&path, identities_file);
-    let status = encrypt_contents_into_file(
-        recipient,
-        recipients_file,
-        global_recipients_file,
-        &path,
-        String::from_utf8(previous_contents).unwrap(),
-    );
-    status
+/// Ask a yes/no `question` on the terminal, even when stdin is piped. Without a terminal
+/// the answer is no.
+fn ask_on_terminal(question: &str) -> bool {
+    let Ok(mut terminal) = File::options().read(true).write(true).open("/dev/tty") else {
+        return false;
+    };
+    if writeln!(terminal, "{}", question).is_err() {
+        return false;
+    }
+    let mut answer = String::new();
+    io::BufReader::new(terminal).read_line(&mut answer).is_ok()
+        && answer.trim().eq_ignore_ascii_case("y")
+}
+
+fn print_output(output: String) {
+    if !output.is_empty() {
+        println!("{}", output);
+    }
 }
 
 fn apply_only_exclude(
@@ -742,17 +1516,16 @@ fn apply_only_exclude(
     } else {
         parsed_env
     };
-    let filtered_env_contents = if let Some(exclude_keys) = exclude {
+    if let Some(exclude_keys) = exclude {
         exclude_env_contents(filtered_env_contents, exclude_keys)
     } else {
         filtered_env_contents
-    };
-    filtered_env_contents
+    }
 }
 
 fn exclude_env_contents(
     env_contents: BTreeMap<String, String>,
-    exclude_keys: &Vec<String>,
+    exclude_keys: &[String],
 ) -> BTreeMap<String, String> {
     env_contents
         .into_iter()
@@ -761,89 +1534,10 @@ fn exclude_env_contents(
 }
 fn filter_env_contents(
     env_contents: BTreeMap<String, String>,
-    only_keys: &Vec<String>,
+    only_keys: &[String],
 ) -> BTreeMap<String, String> {
     env_contents
         .into_iter()
         .filter(|(key, _)| only_keys.contains(key))
         .collect::<BTreeMap<String, String>>()
 }
-
-fn decrypt_file_contents(
-    file: &std::path::PathBuf,
-    identities_file: &std::path::PathBuf,
-) -> Vec<u8> {
-    let file_contents = fs::read(&file).expect("Failed to read environment file");
-    let mut child = std::process::Command::new("age")
-        .arg("-d")
-        .arg("--identity")
-        .arg(&identities_file)
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .spawn()
-        .expect("Failed to spawn age command");
-    {
-        let stdin = child
-            .stdin
-            .as_mut()
-            .expect("Failed to open stdin for age command");
-        stdin
-            .write_all(&file_contents)
-            .expect("Failed to write environment contents to age command");
-    }
-    let status = child.wait().expect("Failed to wait for age command");
-    if !status.success() {
-        panic!(
-            "Failed to run command with status {}: stderr: {:?}",
-            status,
-            child
-                .stderr
-                .expect("Failed to read stderr from age command")
-        );
-    }
-    let mut contents = Vec::new();
-    child
-        .stdout
-        .expect("Failed to open stdout for age command")
-        .read_to_end(&mut contents)
-        .expect("Failed to read stdout from age command");
-    contents
-}
-
-fn encrypt_contents_into_file(
-    recipient: &Option<String>,
-    recipients_file: &Option<String>,
-    global_recipients_file: &Option<PathBuf>,
-    file_path: &PathBuf,
-    filtered_env_contents_string: String,
-) -> std::process::ExitStatus {
-    let mut age_command = std::process::Command::new("age");
-
-    if let Some(recipient) = recipient {
-        age_command.arg("-r").arg(&recipient);
-    }
-    if let Some(recipients_file) = recipients_file {
-        age_command.arg("-R").arg(&recipients_file);
-    }
-    if let Some(global_recipients_file) = global_recipients_file {
-        age_command.arg("-R").arg(&global_recipients_file);
-    }
-
-    age_command.arg("-o").arg(file_path);
-
-    let mut child = age_command
-        .stdin(std::process::Stdio::piped())
-        .spawn()
-        .expect("Failed to spawn age command");
-    {
-        let stdin = child
-            .stdin
-            .as_mut()
-            .expect("Failed to open stdin for age command");
-        stdin
-            .write_all(filtered_env_contents_string.as_bytes())
-            .expect("Failed to write environment contents to age command");
-    }
-    let status = child.wait().expect("Failed to wait for age command");
-    status
-}
@@ -0,0 +1,48 @@
+//! Layering several environments on top of each other
+//!
+//! `show`, `show-for-eval` and `run-with-env` accept more than one environment, like
+//! `-n base -n api -n overrides`. They are merged in order and later environments win.
+use crate::error::{Error, Result};
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+
+/// What to do when two environments set the same key to different values
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnConflict {
+    /// Use the value from the last environment
+    #[default]
+    Override,
+    /// Use the value from the last environment and print a warning
+    Warn,
+    /// Fail without using any of the values
+    Error,
+}
+
+/// Merge `layers`, given as `(name, env)` pairs, in order
+pub fn merge_envs(
+    layers: Vec<(String, BTreeMap<String, String>)>,
+    on_conflict: OnConflict,
+) -> Result<BTreeMap<String, String>> {
+    let mut merged = BTreeMap::new();
+    let mut origins: BTreeMap<String, String> = BTreeMap::new();
+    for (name, env) in layers {
+        for (key, value) in env {
+            if let Some(previous) = merged.get(&key) {
+                if *previous != value {
+                    let message = format!(
+                        "Key {} from environment {} conflicts with environment {}",
+                        key, name, origins[&key]
+                    );
+                    match on_conflict {
+                        OnConflict::Override => {}
+                        OnConflict::Warn => eprintln!("age-env: warning: {}", message),
+                        OnConflict::Error => return Err(Error::InvalidInput(message)),
+                    }
+                }
+            }
+            origins.insert(key.clone(), name.clone());
+            merged.insert(key, value);
+        }
+    }
+    Ok(merged)
+}
@@ -0,0 +1,91 @@
+//! Passphrase encryption
+//!
+//! Environments created with `create --passphrase` and identities files protected with
+//! `set-identities-passphrase` are encrypted with an scrypt passphrase instead of
+//! recipients. Passphrases are read from the terminal without echo.
+use crate::error::{Error, IoContext, Result};
+use age::armor::ArmoredReader;
+use age::secrecy::{ExposeSecret, SecretString};
+use std::io::{Read, Write};
+
+/// Whether `ciphertext` is an age file encrypted with a passphrase
+pub fn is_passphrase_encrypted(ciphertext: &[u8]) -> bool {
+    age::Decryptor::new_buffered(ArmoredReader::new(ciphertext))
+        .map(|decryptor| decryptor.is_scrypt())
+        .unwrap_or(false)
+}
+
+pub fn encrypt_with_passphrase(passphrase: &SecretString, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let encryptor = age::Encryptor::with_user_passphrase(passphrase.clone());
+    let mut encrypted = Vec::new();
+    let mut writer = encryptor
+        .wrap_output(&mut encrypted)
+        .context("Failed to start encryption")?;
+    writer
+        .write_all(plaintext)
+        .context("Failed to encrypt contents")?;
+    writer.finish().context("Failed to finish encryption")?;
+    Ok(encrypted)
+}
+
+pub fn decrypt_with_passphrase(passphrase: &SecretString, ciphertext: &[u8]) -> Result<Vec<u8>> {
+    let decryptor = age::Decryptor::new_buffered(ArmoredReader::new(ciphertext))
+        .map_err(|e| Error::Decrypt(format!("Failed to parse encrypted file: {}", e)))?;
+    let identity = age::scrypt::Identity::new(passphrase.clone());
+    let mut reader = decryptor
+        .decrypt(std::iter::once(&identity as &dyn age::Identity))
+        .map_err(|e| Error::Decrypt(format!("Failed to decrypt: {}", e)))?;
+    let mut contents = Vec::new();
+    reader
+        .read_to_end(&mut contents)
+        .map_err(|e| Error::Decrypt(format!("Failed to decrypt: {}", e)))?;
+    Ok(contents)
+}
+
+/// Ask for a passphrase on the terminal, without echo
+pub fn prompt_passphrase(prompt: &str) -> Result<SecretString> {
+    let passphrase = rpassword::prompt_password(prompt).map_err(|e| {
+        Error::Decrypt(format!(
+            "A passphrase is needed but it could not be read from the terminal: {}",
+            e
+        ))
+    })?;
+    Ok(SecretString::from(passphrase))
+}
+
+/// Ask for a new passphrase twice, refusing empty or mismatched ones
+pub fn prompt_new_passphrase(prompt: &str) -> Result<SecretString> {
+    let passphrase = prompt_passphrase(prompt)?;
+    if passphrase.expose_secret().is_empty() {
+        return Err(Error::InvalidInput(
+            "The passphrase can't be empty".to_string(),
+        ));
+    }
+    let confirmation = prompt_passphrase("Confirm passphrase: ")?;
+    if passphrase.expose_secret() != confirmation.expose_secret() {
+        return Err(Error::InvalidInput("Passphrases didn't match".to_string()));
+    }
+    Ok(passphrase)
+}
+
+/// Asks for the passphrases of encrypted identities files when they are first used
+#[derive(Clone, Copy)]
+pub struct PromptCallbacks;
+
+impl age::Callbacks for PromptCallbacks {
+    fn display_message(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+
+    fn confirm(&self, _message: &str, _yes_string: &str, _no_string: Option<&str>) -> Option<bool> {
+        None
+    }
+
+    fn request_public_string(&self, _description: &str) -> Option<String> {
+        None
+    }
+
+    fn request_passphrase(&self, description: &str) -> Option<SecretString> {
+        prompt_passphrase(&format!("{}: ", description)).ok()
+    }
+}
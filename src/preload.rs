@@ -0,0 +1,154 @@
+//! Preloaded environments
+//!
+//! `show-for-eval --preload` exports decrypted environments into `AGE_ENV_PRELOAD_B64`
+//! as `name:base64;other-name:base64`, so later commands in the same shell can skip
+//! decrypting with the user's identities. Each entry is encrypted with an ephemeral
+//! session key kept in the runtime directory, outside the environment, so the variable
+//! never holds plaintext. The session key is replaced once it is older than
+//! [`SESSION_KEY_TTL`], so a copied key or variable doesn't stay useful. The preload is
+//! only a cache: entries that can't be decrypted, for example after the session key was
+//! removed or replaced, are decrypted from the store again.
+use crate::dotenv::serialize_stored_env;
+use crate::error::{Error, IoContext, Result};
+use crate::storage::{create_private_dir, runtime_dir, TEMP_FILE_MARKER};
+use crate::store::parse_env_contents;
+use age::secrecy::ExposeSecret;
+use age::x25519;
+use base64::prelude::*;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const PRELOAD_ENV_VAR: &str = "AGE_ENV_PRELOAD_B64";
+
+/// How long a session key is used before it is replaced
+pub const SESSION_KEY_TTL: Duration = Duration::from_secs(12 * 60 * 60);
+
+const SESSION_KEY_FILE: &str = "preload.key";
+
+/// Add environment `name` to the preload data `current`, unless it is already there and
+/// still readable with the current session key
+pub fn encode_preload(current: &str, name: &str, env: &BTreeMap<String, String>) -> Result<String> {
+    if decode_preload(current, name)?.is_some() {
+        return Ok(current.to_string());
+    }
+    let current = current
+        .split(';')
+        .filter(|entry| {
+            entry
+                .split_once(':')
+                .is_some_and(|(entry_name, _)| entry_name != name)
+        })
+        .collect::<Vec<&str>>()
+        .join(";");
+    let session_key = session_key(true)?.expect("Session key is created when missing");
+    let encrypted = age::encrypt(
+        &session_key.to_public(),
+        serialize_stored_env(env).as_bytes(),
+    )
+    .map_err(|err| Error::Encrypt(format!("Failed to encrypt preload data: {}", err)))?;
+    let encoded_data = BASE64_STANDARD.encode(encrypted);
+    Ok(if current.is_empty() {
+        format!("{}:{}", name, encoded_data)
+    } else {
+        format!("{};{}:{}", current, name, encoded_data)
+    })
+}
+
+/// Environment `name` from the preload data `data`, if it was preloaded in this session
+pub fn decode_preload(data: &str, name: &str) -> Result<Option<BTreeMap<String, String>>> {
+    let Some(encoded_data) = find_entry(data, name) else {
+        return Ok(None);
+    };
+    let Ok(encrypted) = BASE64_STANDARD.decode(encoded_data) else {
+        return Ok(None);
+    };
+    let Some(session_key) = session_key(false)? else {
+        return Ok(None);
+    };
+    match age::decrypt(&session_key, &encrypted) {
+        Ok(decrypted) => parse_env_contents(name, decrypted).map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+fn session_key_file() -> PathBuf {
+    runtime_dir().join(SESSION_KEY_FILE)
+}
+
+/// The session key, generated first if `create` is set and there is none or it expired
+fn session_key(create: bool) -> Result<Option<x25519::Identity>> {
+    let file = session_key_file();
+    loop {
+        match fs::read_to_string(&file) {
+            Ok(_) if is_expired(&file) => {
+                match fs::remove_file(&file) {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == ErrorKind::NotFound => {}
+                    Err(err) => {
+                        return Err(err).context("Failed to delete expired preload session key")
+                    }
+                }
+                if !create {
+                    return Ok(None);
+                }
+            }
+            Ok(contents) => {
+                return contents.trim().parse().map(Some).map_err(|_| {
+                    Error::InvalidInput(format!(
+                        "Invalid preload session key {:?}, delete it to start a new session",
+                        file
+                    ))
+                })
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound && create => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context("Failed to read preload session key"),
+        }
+        create_private_dir(
+            file.parent()
+                .expect("Session key file has no parent directory"),
+        )?;
+        let identity = x25519::Identity::generate();
+        // Write the key completely before linking it into place, so readers never see
+        // a partial key
+        let temp_file = file.with_file_name(format!(
+            "{}{}{}",
+            SESSION_KEY_FILE,
+            TEMP_FILE_MARKER,
+            std::process::id()
+        ));
+        let mut options = File::options();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let written = options.open(&temp_file).and_then(|mut opened| {
+            opened.write_all(identity.to_string().expose_secret().as_bytes())?;
+            opened.sync_all()
+        });
+        let linked = written.and_then(|_| fs::hard_link(&temp_file, &file));
+        let _ = fs::remove_file(&temp_file);
+        match linked {
+            Ok(()) => return Ok(Some(identity)),
+            // Another process created it first, read theirs
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err).context("Failed to create preload session key"),
+        }
+    }
+}
+
+/// Whether session key `file` is older than [`SESSION_KEY_TTL`]
+fn is_expired(file: &Path) -> bool {
+    fs::metadata(file)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > SESSION_KEY_TTL))
+}
+
+fn find_entry<'a>(data: &'a str, name: &str) -> Option<&'a str> {
+    data.split(';')
+        .filter_map(|entry| entry.split_once(':'))
+        .find(|(entry_name, _)| *entry_name == name)
+        .map(|(_, encoded_data)| encoded_data)
+}
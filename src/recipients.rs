@@ -0,0 +1,249 @@
+//! Per-environment recipient lists
+//!
+//! Environments encrypted to explicit recipients remember them in `env-recipients/<name>`,
+//! using the same one-recipient-per-line format as the global recipients file. The others
+//! follow the global recipients file, so they pick up its changes when reencrypted.
+//!
+//! A recipient is a public key, optionally named as `alice <age1...>` or
+//! `alice alice@example.com <ssh-ed25519 AAAA...>`. Per environment lists and
+//! `--recipient` can also refer to a named recipient of the global recipients file by
+//! its name, or to a group as `@ops`.
+use crate::crypto::CryptoBackend;
+use crate::envs::remove_empty_parents;
+use crate::error::{Error, IoContext, Result};
+use crate::storage::{append_atomic, write_atomic};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub fn env_recipients_file(config_dir: &Path, name: &str) -> PathBuf {
+    config_dir.join("env-recipients").join(name)
+}
+
+pub fn read_env_recipients(
+    backend: &dyn CryptoBackend,
+    config_dir: &Path,
+    name: &str,
+) -> Result<Option<Vec<String>>> {
+    let file = env_recipients_file(config_dir, name);
+    if !file.exists() {
+        return Ok(None);
+    }
+    backend.list_recipients(&file).map(Some)
+}
+
+pub fn write_env_recipients(config_dir: &Path, name: &str, recipients: &[String]) -> Result<()> {
+    let file = env_recipients_file(config_dir, name);
+    let parent = file
+        .parent()
+        .expect("Recipients file has no parent directory");
+    fs::create_dir_all(parent).context("Failed to create env-recipients directory")?;
+    let contents = recipients
+        .iter()
+        .map(|recipient| format!("{}\n", recipient))
+        .collect::<String>();
+    write_atomic(&file, contents.as_bytes())
+}
+
+pub fn remove_env_recipients(config_dir: &Path, name: &str) -> Result<()> {
+    let file = env_recipients_file(config_dir, name);
+    if file.exists() {
+        fs::remove_file(&file).context("Failed to delete environment recipients file")?;
+        remove_empty_parents(&config_dir.join("env-recipients"), &file)?;
+    }
+    Ok(())
+}
+
+/// Work out who an environment should be encrypted to.
+///
+/// Explicit `--recipient`/`--recipients-file` values are combined with the global
+/// recipients file, as before. Without them the environment's stored recipients are
+/// reused, falling back to the global recipients file for environments that have none.
+pub fn resolve_recipients(
+    backend: &dyn CryptoBackend,
+    stored_recipients: Option<Vec<String>>,
+    recipient: &Option<String>,
+    recipients_file: &Option<String>,
+    global_recipients_file: &Option<PathBuf>,
+    no_global_recipients: bool,
+) -> Result<Vec<String>> {
+    let explicit = recipient.is_some() || recipients_file.is_some();
+    let mut recipients = Vec::new();
+    if explicit {
+        recipients.extend(recipient.iter().cloned());
+        if let Some(recipients_file) = recipients_file {
+            recipients.extend(backend.list_recipients(Path::new(recipients_file))?);
+        }
+    } else if let Some(stored_recipients) = stored_recipients {
+        recipients.extend(stored_recipients);
+    }
+    let use_global = if explicit {
+        !no_global_recipients
+    } else {
+        recipients.is_empty()
+    };
+    if use_global {
+        if let Some(global_recipients_file) = global_recipients_file {
+            recipients.extend(backend.list_recipients(global_recipients_file)?);
+        }
+    }
+    Ok(dedup_recipients(recipients))
+}
+
+/// The recipients given with `--recipient`/`--recipients-file`, combined with the global
+/// recipients file unless `no_global_recipients`, or `None` without them
+pub fn explicit_recipients(
+    backend: &dyn CryptoBackend,
+    recipient: &Option<String>,
+    recipients_file: &Option<String>,
+    global_recipients_file: &Option<PathBuf>,
+    no_global_recipients: bool,
+) -> Result<Option<Vec<String>>> {
+    if recipient.is_none() && recipients_file.is_none() {
+        return Ok(None);
+    }
+    resolve_recipients(
+        backend,
+        None,
+        recipient,
+        recipients_file,
+        global_recipients_file,
+        no_global_recipients,
+    )
+    .map(Some)
+}
+
+/// The public key of `recipient`, without its name
+pub fn recipient_key(recipient: &str) -> &str {
+    match named_recipient(recipient) {
+        Some((_, key)) => key,
+        None => recipient,
+    }
+}
+
+/// The name of `recipient`, written as `name [email] <key>`
+pub fn recipient_name(recipient: &str) -> Option<&str> {
+    named_recipient(recipient).and_then(|(label, _)| label.split_whitespace().next())
+}
+
+fn named_recipient(recipient: &str) -> Option<(&str, &str)> {
+    let (label, key) = recipient.strip_suffix('>')?.rsplit_once('<')?;
+    Some((label.trim(), key.trim()))
+}
+
+/// Check that `recipient` is an age public key, a plugin recipient like `age1yubikey1...`
+/// or an `ssh-ed25519`/`ssh-rsa` public key, optionally named
+pub fn validate_recipient(recipient: &str) -> Result<()> {
+    if let Some((label, key)) = named_recipient(recipient) {
+        return match recipient_name(recipient) {
+            Some(name) if !name.starts_with('@') && !name.contains(['<', '>']) => {
+                validate_recipient(key)
+            }
+            _ => Err(Error::InvalidInput(format!(
+                "Invalid recipient name {:?} in {:?}, use names like `alice <age1...>`",
+                label, recipient
+            ))),
+        };
+    }
+    if recipient.starts_with('@') {
+        return Err(Error::InvalidInput(format!(
+            "Group {} can only be used with --recipient or `age-env recipients <name> --add`",
+            recipient
+        )));
+    }
+    if recipient.parse::<age::x25519::Recipient>().is_ok()
+        || recipient.parse::<age::plugin::Recipient>().is_ok()
+    {
+        return Ok(());
+    }
+    if !recipient.starts_with("ssh-") {
+        return Err(Error::InvalidInput(format!(
+            "Invalid recipient {:?}, expected an age public key (age1...), a plugin recipient or an ssh-ed25519 or ssh-rsa public key",
+            recipient
+        )));
+    }
+    recipient
+        .parse::<age::ssh::Recipient>()
+        .map(|_| ())
+        .map_err(|error| {
+            let reason = match error {
+                age::ssh::ParseRecipientKeyError::Unsupported(key_type) => {
+                    format!(
+                        "{} keys are not supported, use ssh-ed25519 or ssh-rsa",
+                        key_type
+                    )
+                }
+                age::ssh::ParseRecipientKeyError::RsaModulusTooSmall => {
+                    "the RSA key is too small".to_string()
+                }
+                age::ssh::ParseRecipientKeyError::RsaModulusTooLarge => {
+                    "the RSA key is too large".to_string()
+                }
+                _ => "it is not a valid SSH public key".to_string(),
+            };
+            Error::InvalidInput(format!("Invalid recipient {:?}: {}", recipient, reason))
+        })
+}
+
+/// Whether `a` and `b` are the same key, ignoring names and the labels of SSH keys
+pub fn same_recipient(a: &str, b: &str) -> bool {
+    recipient_key(a)
+        .split_whitespace()
+        .take(2)
+        .eq(recipient_key(b).split_whitespace().take(2))
+}
+
+/// Append the valid `recipients` that `recipients_file` doesn't have yet, each under a
+/// `label` comment, returning them
+pub fn add_recipients(
+    backend: &dyn CryptoBackend,
+    recipients_file: &Path,
+    recipients: &[String],
+    label: Option<&str>,
+) -> Result<Vec<String>> {
+    for recipient in recipients {
+        validate_recipient(recipient)?;
+    }
+    let mut known = if recipients_file.exists() {
+        backend.list_recipients(recipients_file)?
+    } else {
+        Vec::new()
+    };
+    let mut added = Vec::new();
+    for recipient in recipients {
+        if known.iter().any(|known| same_recipient(known, recipient)) {
+            continue;
+        }
+        if let Some(name) = recipient_name(recipient) {
+            if known
+                .iter()
+                .any(|known| recipient_name(known) == Some(name))
+            {
+                return Err(Error::InvalidInput(format!(
+                    "There already is a recipient named {} in {:?}",
+                    name, recipients_file
+                )));
+            }
+        }
+        known.push(recipient.clone());
+        added.push(recipient.clone());
+    }
+    let contents = added
+        .iter()
+        .map(|recipient| match label {
+            Some(label) => format!("# {}\n{}\n", label, recipient),
+            None => format!("{}\n", recipient),
+        })
+        .collect::<String>();
+    append_atomic(recipients_file, contents.as_bytes())?;
+    Ok(added)
+}
+
+pub fn dedup_recipients(recipients: Vec<String>) -> Vec<String> {
+    let mut deduped: Vec<String> = Vec::new();
+    for recipient in recipients {
+        if !deduped.contains(&recipient) {
+            deduped.push(recipient);
+        }
+    }
+    deduped
+}
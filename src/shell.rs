@@ -0,0 +1,112 @@
+//! Shell-specific output for `show-for-eval`
+//!
+//! Every value is quoted so that evaluating the output only ever assigns variables,
+//! whatever the value contains.
+use crate::error::{Error, Result};
+use clap::ValueEnum;
+use std::env;
+use std::path::Path;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvalShell {
+    /// sh, bash, zsh and other POSIX shells
+    #[value(alias = "sh", alias = "bash", alias = "zsh")]
+    Posix,
+    Fish,
+    #[value(alias = "nushell")]
+    Nu,
+    #[value(alias = "pwsh")]
+    Powershell,
+    /// cmd.exe, for use in batch files
+    Cmd,
+}
+
+impl EvalShell {
+    /// Guess the shell from `$SHELL`, falling back to POSIX
+    pub fn detect() -> Self {
+        let shell = env::var("SHELL").unwrap_or_default();
+        let name = Path::new(&shell)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+            .trim_end_matches(".exe")
+            .to_string();
+        match name.as_str() {
+            "fish" => EvalShell::Fish,
+            "nu" => EvalShell::Nu,
+            "pwsh" | "powershell" => EvalShell::Powershell,
+            "cmd" => EvalShell::Cmd,
+            _ => EvalShell::Posix,
+        }
+    }
+
+    /// A statement exporting `key` with `value` to the environment
+    pub fn export(&self, key: &str, value: &str) -> Result<String> {
+        Ok(match self {
+            EvalShell::Posix => format!("export {}={}", key, posix_quote(value)),
+            EvalShell::Fish => format!("set -gx {} {}", key, fish_quote(value)),
+            EvalShell::Nu => format!("$env.{} = {}", key, nu_quote(value)),
+            EvalShell::Powershell => format!("$env:{} = {}", key, powershell_quote(value)),
+            EvalShell::Cmd => format!("set \"{}={}\"", key, cmd_escape(key, value)?),
+        })
+    }
+}
+
+/// Single-quote for POSIX shells, where nothing inside single quotes is special
+pub fn posix_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn fish_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn nu_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Single-quote for PowerShell, which also ends single-quoted strings at the curly
+/// single quotes `‘ ’ ‚ ‛`, so those are doubled too
+fn powershell_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('\'');
+    for c in value.chars() {
+        if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}') {
+            quoted.push(c);
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// Escape for `set "KEY=value"`. cmd has no way to escape a `"` inside the quotes, or a
+/// `!` when delayed expansion is on, so values with them are refused.
+fn cmd_escape(key: &str, value: &str) -> Result<String> {
+    if value.contains('\n') || value.contains('\r') {
+        return Err(Error::InvalidInput(format!(
+            "Value of {} contains a newline, which cmd cannot represent",
+            key
+        )));
+    }
+    if let Some(c) = value.chars().find(|c| matches!(c, '"' | '!')) {
+        return Err(Error::InvalidInput(format!(
+            "Value of {} contains {}, which cmd cannot represent safely",
+            key, c
+        )));
+    }
+    Ok(value.replace('%', "%%"))
+}
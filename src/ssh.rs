@@ -0,0 +1,93 @@
+//! SSH keys as recipients and identities
+//!
+//! `ssh-ed25519` and `ssh-rsa` public keys can be used as recipients, written as in an
+//! `authorized_keys` file: `<type> <base64 key> <comment>`. `import-recipients` reads them
+//! from an `authorized_keys` file or a saved `https://github.com/<user>.keys` dump.
+//! The matching private key, by default `~/.ssh/id_ed25519`, decrypts alongside the
+//! identities file.
+use crate::error::{Error, Result};
+use std::env;
+use std::path::PathBuf;
+
+/// SSH key types age can encrypt to
+pub const SUPPORTED_KEY_TYPES: &[&str] = &["ssh-ed25519", "ssh-rsa"];
+
+/// Prefixes of the other SSH key types, which are skipped with a warning
+const UNSUPPORTED_KEY_PREFIXES: &[&str] = &["ssh-", "ecdsa-", "sk-"];
+
+/// Private key used for decryption when `--ssh-identity` is not passed, if it exists
+pub fn default_ssh_identity() -> Option<PathBuf> {
+    let file = PathBuf::from(env::var_os("HOME")?)
+        .join(".ssh")
+        .join("id_ed25519");
+    file.exists().then_some(file)
+}
+
+/// A public key read from an `authorized_keys` line
+pub struct SshPublicKey {
+    pub key_type: String,
+    pub key: String,
+    pub comment: Option<String>,
+}
+
+impl SshPublicKey {
+    /// The key as a recipient line, labelled with its comment or `label`
+    pub fn to_recipient(&self, label: Option<&str>) -> String {
+        match self.comment.as_deref().or(label) {
+            Some(comment) => format!("{} {} {}", self.key_type, self.key, comment),
+            None => format!("{} {}", self.key_type, self.key),
+        }
+    }
+}
+
+/// Read the keys of an `authorized_keys` file, or of a `<user>.keys` dump, which uses the
+/// same format without comments or options. Keys age can't encrypt to are skipped with
+/// a warning naming `source`.
+pub fn parse_authorized_keys(source: &str, contents: &str) -> Result<Vec<SshPublicKey>> {
+    let mut keys = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words = line.split_whitespace().collect::<Vec<&str>>();
+        // Lines can start with options, like `from="10.0.0.1" ssh-ed25519 AAAA...`
+        let Some(position) = words.iter().position(|word| {
+            UNSUPPORTED_KEY_PREFIXES
+                .iter()
+                .any(|prefix| word.starts_with(prefix))
+        }) else {
+            return Err(Error::InvalidInput(format!(
+                "No SSH key on line {} of {}",
+                index + 1,
+                source
+            )));
+        };
+        let key_type = words[position];
+        if !SUPPORTED_KEY_TYPES.contains(&key_type) {
+            eprintln!(
+                "age-env: warning: Skipping {} key on line {} of {}, only {} keys are supported",
+                key_type,
+                index + 1,
+                source,
+                SUPPORTED_KEY_TYPES.join(" and ")
+            );
+            continue;
+        }
+        let Some(key) = words.get(position + 1) else {
+            return Err(Error::InvalidInput(format!(
+                "Missing {} key on line {} of {}",
+                key_type,
+                index + 1,
+                source
+            )));
+        };
+        let comment = words[position + 2..].join(" ");
+        keys.push(SshPublicKey {
+            key_type: key_type.to_string(),
+            key: key.to_string(),
+            comment: (!comment.is_empty()).then_some(comment),
+        });
+    }
+    Ok(keys)
+}
@@ -0,0 +1,168 @@
+//! Safe writes to the config directory
+//!
+//! Files are written to a temporary file next to their destination, synced and then
+//! renamed into place, so an interrupted write never leaves a truncated file behind.
+//! Commands also take an advisory lock on the config directory: a shared one to read,
+//! an exclusive one to write.
+//!
+//! Files that must not outlive the login session, like the agent socket, live in a
+//! private runtime directory instead.
+use crate::error::{Error, IoContext, Result};
+use std::env;
+use std::fs::{self, File, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Part of the name of temporary files, which are skipped when listing environments
+pub const TEMP_FILE_MARKER: &str = ".age-env-tmp-";
+
+const LOCK_FILE: &str = ".lock";
+
+/// Replace the contents of `path` with `contents` atomically, keeping its permissions.
+/// New files are only readable by the current user, like `age-keygen` output.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::InvalidInput(format!("Invalid file name {:?}", path)))?;
+    let temp_path = path.with_file_name(format!(
+        "{}{}{}",
+        file_name,
+        TEMP_FILE_MARKER,
+        std::process::id()
+    ));
+    let mut options = File::options();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let result = options
+        .open(&temp_path)
+        .and_then(|mut file| {
+            if let Ok(metadata) = fs::metadata(path) {
+                file.set_permissions(metadata.permissions())?;
+            }
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result.with_context(|| format!("Failed to write {:?}", path))?;
+    sync_parent(path);
+    Ok(())
+}
+
+/// Make the rename durable
+fn sync_parent(path: &Path) {
+    #[cfg(unix)]
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+/// Append `contents` to `path` atomically, creating it if needed
+pub fn append_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut existing = if path.exists() {
+        fs::read(path).with_context(|| format!("Failed to read {:?}", path))?
+    } else {
+        Vec::new()
+    };
+    existing.extend_from_slice(contents);
+    write_atomic(path, &existing)
+}
+
+/// Lock `config_dir` until the returned file is dropped, waiting for other processes
+/// holding a conflicting lock
+pub fn lock_config_dir(config_dir: &Path, exclusive: bool) -> Result<File> {
+    let lock_path = config_dir.join(LOCK_FILE);
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .context("Failed to open lock file")?;
+    let attempt = if exclusive {
+        file.try_lock()
+    } else {
+        file.try_lock_shared()
+    };
+    match attempt {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            eprintln!(
+                "Waiting for another age-env process to release {:?}",
+                lock_path
+            );
+            let locked = if exclusive {
+                file.lock()
+            } else {
+                file.lock_shared()
+            };
+            locked.context("Failed to lock config directory")?;
+        }
+        Err(TryLockError::Error(err)) => {
+            return Err(err).context("Failed to lock config directory");
+        }
+    }
+    Ok(file)
+}
+
+/// Per-user directory for session files: `$XDG_RUNTIME_DIR/age-env`, or `age-env-<uid>`
+/// in the temp directory
+pub fn runtime_dir() -> PathBuf {
+    match env::var("XDG_RUNTIME_DIR") {
+        Ok(runtime_dir) => PathBuf::from(runtime_dir).join("age-env"),
+        #[cfg(unix)]
+        Err(_) => env::temp_dir().join(format!("age-env-{}", current_uid())),
+        #[cfg(not(unix))]
+        Err(_) => env::temp_dir().join("age-env"),
+    }
+}
+
+/// Create `dir` accessible only to the current user, or check that an existing one
+/// belongs to them and is not accessible to anyone else
+pub fn create_private_dir(dir: &Path) -> Result<()> {
+    if !dir.exists() {
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+            .create(dir)
+            .with_context(|| format!("Failed to create directory {:?}", dir))?;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let metadata =
+            fs::metadata(dir).with_context(|| format!("Failed to read directory {:?}", dir))?;
+        if metadata.uid() != current_uid() {
+            return Err(Error::InvalidInput(format!(
+                "Directory {:?} belongs to another user",
+                dir
+            )));
+        }
+        if metadata.mode() & 0o077 != 0 {
+            return Err(Error::InvalidInput(format!(
+                "Directory {:?} is accessible to other users, run `chmod 700 {}`",
+                dir,
+                dir.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn current_uid() -> u32 {
+    unsafe { libc::getuid() }
+}
@@ -0,0 +1,463 @@
+//! High level access to a config directory
+#[cfg(unix)]
+use crate::agent::AgentClient;
+use crate::crypto::{encrypt_contents, BackendKind, CryptoBackend};
+use crate::dotenv::{parse_stored_env, serialize_stored_env};
+use crate::envs::{check_env_path, env_file, list_envs, remove_empty_parents};
+use crate::error::{Error, IoContext, Result};
+use crate::groups::{clear_reencryption_flag, resolve_recipient_keys};
+use crate::history::{record_deletion, record_version, snapshot_before_change};
+use crate::identities::{identities_for_env, remove_env_identities};
+use crate::inherit::{resolve_env, ResolvedEnv};
+use crate::interpolate::interpolate_env;
+use crate::passphrase::{
+    decrypt_with_passphrase, encrypt_with_passphrase, is_passphrase_encrypted, prompt_passphrase,
+};
+use crate::recipients::{
+    read_env_recipients, remove_env_recipients, resolve_recipients, write_env_recipients,
+};
+use crate::storage::{lock_config_dir, write_atomic};
+use age::secrecy::SecretString;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// A config directory holding encrypted environments, like `~/.age-env`
+///
+/// ```no_run
+/// let store = age_env::Store::open("/home/me/.age-env")?;
+/// let env = store.load_resolved("github-token")?;
+/// println!("{}", env["GITHUB_TOKEN"]);
+/// # Ok::<(), age_env::Error>(())
+/// ```
+pub struct Store {
+    config_dir: PathBuf,
+    backend: Box<dyn CryptoBackend>,
+    identities_file: PathBuf,
+    recipients_file: PathBuf,
+    ssh_identity: Option<PathBuf>,
+    #[cfg(unix)]
+    agent: Option<AgentClient>,
+    /// Passphrases entered for passphrase protected environments, to write them back
+    passphrases: RefCell<BTreeMap<String, SecretString>>,
+    lock: RefCell<Option<HeldLock>>,
+}
+
+/// The lock on the config directory, shared by the nested `Store::lock` calls holding it
+struct HeldLock {
+    _file: File,
+    exclusive: bool,
+    holders: usize,
+}
+
+/// Keeps the config directory locked until it is dropped, see `Store::lock`
+pub struct StoreLock<'a> {
+    store: &'a Store,
+}
+
+impl Drop for StoreLock<'_> {
+    fn drop(&mut self) {
+        let mut lock = self.store.lock.borrow_mut();
+        if let Some(held) = lock.as_mut() {
+            held.holders -= 1;
+            if held.holders == 0 {
+                *lock = None;
+            }
+        }
+    }
+}
+
+impl Store {
+    /// Open an existing config directory, using the native backend and the
+    /// `identities` and `recipients` files inside it
+    pub fn open(config_dir: impl Into<PathBuf>) -> Result<Self> {
+        let config_dir = config_dir.into();
+        if !config_dir.is_dir() {
+            return Err(Error::InvalidInput(format!(
+                "Config directory {:?} does not exist",
+                config_dir
+            )));
+        }
+        Ok(Store {
+            identities_file: config_dir.join("identities"),
+            recipients_file: config_dir.join("recipients"),
+            ssh_identity: None,
+            backend: BackendKind::Native.build()?,
+            #[cfg(unix)]
+            agent: None,
+            passphrases: RefCell::default(),
+            lock: RefCell::default(),
+            config_dir,
+        })
+    }
+
+    pub fn with_backend(mut self, backend: Box<dyn CryptoBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Decrypt environments without their own identities with `identities_file`
+    pub fn with_identities_file(mut self, identities_file: impl Into<PathBuf>) -> Self {
+        self.identities_file = identities_file.into();
+        self
+    }
+
+    /// Encrypt environments without their own recipients to `recipients_file`
+    pub fn with_recipients_file(mut self, recipients_file: impl Into<PathBuf>) -> Self {
+        self.recipients_file = recipients_file.into();
+        self
+    }
+
+    /// Also decrypt with the SSH private key `ssh_identity`, like `~/.ssh/id_ed25519`
+    pub fn with_ssh_identity(mut self, ssh_identity: impl Into<PathBuf>) -> Self {
+        self.ssh_identity = Some(ssh_identity.into());
+        self
+    }
+
+    /// Ask `agent` for decrypted environments before decrypting them, and cache them there
+    #[cfg(unix)]
+    pub fn with_agent(mut self, agent: AgentClient) -> Self {
+        self.agent = Some(agent);
+        self
+    }
+
+    pub fn config_dir(&self) -> &Path {
+        &self.config_dir
+    }
+
+    pub fn envs_dir(&self) -> PathBuf {
+        self.config_dir.join("envs")
+    }
+
+    pub fn backend(&self) -> &dyn CryptoBackend {
+        self.backend.as_ref()
+    }
+
+    pub fn identities_file(&self) -> &Path {
+        &self.identities_file
+    }
+
+    pub fn ssh_identity(&self) -> Option<&Path> {
+        self.ssh_identity.as_deref()
+    }
+
+    pub fn recipients_file(&self) -> &Path {
+        &self.recipients_file
+    }
+
+    /// The global recipients file, if it exists
+    pub fn global_recipients_file(&self) -> Option<PathBuf> {
+        self.recipients_file
+            .exists()
+            .then(|| self.recipients_file.clone())
+    }
+
+    /// Path of the encrypted file for environment `name`
+    pub fn env_file(&self, name: &str) -> Result<PathBuf> {
+        env_file(&self.envs_dir(), name)
+    }
+
+    /// Names of all environments, sorted
+    pub fn list(&self) -> Result<Vec<String>> {
+        let envs_dir = self.envs_dir();
+        if !envs_dir.exists() {
+            return Ok(Vec::new());
+        }
+        list_envs(&envs_dir)
+    }
+
+    pub fn exists(&self, name: &str) -> Result<bool> {
+        Ok(self.env_file(name)?.is_file())
+    }
+
+    /// Decrypt and parse environment `name`, as stored, without resolving `@extends`
+    pub fn load(&self, name: &str) -> Result<BTreeMap<String, String>> {
+        let file = self.env_file(name)?;
+        if !file.is_file() {
+            return Err(Error::EnvNotFound(name.to_string()));
+        }
+        self.load_file(name, &file)
+    }
+
+    /// Decrypt and parse `file`, which holds environment `name` or one of its versions
+    pub fn load_file(&self, name: &str, file: &Path) -> Result<BTreeMap<String, String>> {
+        parse_env_contents(name, self.decrypt(name, file)?)
+    }
+
+    /// Decrypt `file`, which holds environment `name` or one of its versions, through the
+    /// agent when there is one
+    fn decrypt(&self, name: &str, file: &Path) -> Result<Vec<u8>> {
+        let ciphertext = fs::read(file)
+            .with_context(|| format!("Failed to read environment file {:?}", file))?;
+        #[cfg(unix)]
+        if let Some(plaintext) = self.agent.as_ref().and_then(|agent| agent.get(&ciphertext)) {
+            return Ok(plaintext);
+        }
+        let plaintext = if is_passphrase_encrypted(&ciphertext) {
+            let passphrase = match self.passphrases.borrow().get(name) {
+                Some(passphrase) => passphrase.clone(),
+                None => prompt_passphrase(&format!("Passphrase for environment {}: ", name))?,
+            };
+            let plaintext = decrypt_with_passphrase(&passphrase, &ciphertext)?;
+            self.passphrases
+                .borrow_mut()
+                .insert(name.to_string(), passphrase);
+            plaintext
+        } else {
+            let identities = self.identities_for(name)?;
+            self.backend.decrypt(&identities, &ciphertext)?
+        };
+        #[cfg(unix)]
+        if let Some(agent) = &self.agent {
+            agent.put(&ciphertext, &plaintext);
+        }
+        Ok(plaintext)
+    }
+
+    /// Identity files for environment `name`, with the SSH identity after them
+    fn identities_for(&self, name: &str) -> Result<Vec<PathBuf>> {
+        let mut identities = identities_for_env(&self.config_dir, name, &self.identities_file)?;
+        if let Some(ssh_identity) = &self.ssh_identity {
+            // The SSH key is enough without an identities file
+            identities.retain(|file| *file != self.identities_file || file.exists());
+            if !identities.contains(ssh_identity) {
+                identities.push(ssh_identity.clone());
+            }
+        }
+        Ok(identities)
+    }
+
+    /// Whether environment `name` is encrypted with a passphrase instead of recipients
+    pub fn is_passphrase_protected(&self, name: &str) -> Result<bool> {
+        let file = self.env_file(name)?;
+        if !file.is_file() {
+            return Ok(false);
+        }
+        let ciphertext = fs::read(&file)
+            .with_context(|| format!("Failed to read environment file {:?}", file))?;
+        Ok(is_passphrase_encrypted(&ciphertext))
+    }
+
+    /// The passphrase of passphrase protected environment `name`, asking for it unless it
+    /// was already entered
+    fn passphrase(&self, name: &str) -> Result<SecretString> {
+        if let Some(passphrase) = self.passphrases.borrow().get(name) {
+            return Ok(passphrase.clone());
+        }
+        let passphrase = prompt_passphrase(&format!("Passphrase for environment {}: ", name))?;
+        let ciphertext =
+            fs::read(self.env_file(name)?).context("Failed to read environment file")?;
+        decrypt_with_passphrase(&passphrase, &ciphertext)?;
+        self.passphrases
+            .borrow_mut()
+            .insert(name.to_string(), passphrase.clone());
+        Ok(passphrase)
+    }
+
+    /// Lock the config directory against other processes until the returned guard is
+    /// dropped: shared to read, exclusive to write. Writes take the exclusive lock
+    /// themselves, so this is only needed to keep several calls consistent, like a load
+    /// and the save that follows it. Nested calls reuse the lock already held. A shared
+    /// lock can't be turned into an exclusive one without letting another writer in
+    /// between, so asking for the exclusive lock while holding the shared one fails: take
+    /// the exclusive lock up front instead.
+    pub fn lock(&self, exclusive: bool) -> Result<StoreLock<'_>> {
+        let mut lock = self.lock.borrow_mut();
+        match lock.as_mut() {
+            Some(held) if held.exclusive || !exclusive => held.holders += 1,
+            Some(_) => {
+                return Err(Error::InvalidInput(format!(
+                    "The config directory {:?} is locked for reading, take the exclusive lock before writing",
+                    self.config_dir
+                )))
+            }
+            None => {
+                *lock = Some(HeldLock {
+                    _file: lock_config_dir(&self.config_dir, exclusive)?,
+                    exclusive,
+                    holders: 1,
+                })
+            }
+        }
+        Ok(StoreLock { store: self })
+    }
+
+    /// Load environment `name` with the environments it extends applied
+    pub fn resolve(&self, name: &str) -> Result<ResolvedEnv> {
+        resolve_env(name, self.load(name)?, |parent| self.load(parent))
+    }
+
+    /// Load environment `name` as commands show it: with the environments it extends
+    /// applied and, if it has `@interpolate=true`, its references expanded
+    pub fn load_resolved(&self, name: &str) -> Result<BTreeMap<String, String>> {
+        interpolate_env(name, self.resolve(name)?, |other| self.resolve(other))
+    }
+
+    /// Recipients environment `name` is encrypted to: its own, or the global ones
+    pub fn recipients(&self, name: &str) -> Result<Vec<String>> {
+        resolve_recipients(
+            self.backend(),
+            read_env_recipients(self.backend(), &self.config_dir, name)?,
+            &None,
+            &None,
+            &self.global_recipients_file(),
+            false,
+        )
+    }
+
+    /// Encrypt `env` into environment `name` for `recipients`, creating or replacing it.
+    /// With `None` the environment keeps its own recipients, or follows the global ones.
+    pub fn save(
+        &self,
+        name: &str,
+        env: &BTreeMap<String, String>,
+        recipients: Option<&[String]>,
+    ) -> Result<()> {
+        self.save_with_action(name, env, recipients, "save")
+    }
+
+    /// Like `save`, with `action` recorded in the environment's history. Passphrase
+    /// protected environments stay encrypted with their passphrase instead.
+    pub fn save_with_action(
+        &self,
+        name: &str,
+        env: &BTreeMap<String, String>,
+        recipients: Option<&[String]>,
+        action: &str,
+    ) -> Result<()> {
+        let _lock = self.lock(true)?;
+        if self.is_passphrase_protected(name)? {
+            return self.save_with_passphrase(name, env, &self.passphrase(name)?, action);
+        }
+        self.save_for_recipients(name, env, recipients, action)
+    }
+
+    /// Encrypt `env` into environment `name` for `recipients`, even if it is currently
+    /// passphrase protected. Explicit `recipients` are remembered for the environment.
+    pub fn save_for_recipients(
+        &self,
+        name: &str,
+        env: &BTreeMap<String, String>,
+        recipients: Option<&[String]>,
+        action: &str,
+    ) -> Result<()> {
+        let _lock = self.lock(true)?;
+        check_env_path(&self.envs_dir(), name)?;
+        let file = self.env_file(name)?;
+        fs::create_dir_all(file.parent().unwrap())
+            .context("Failed to create environment folder")?;
+        let keys = self.recipient_keys(&self.recipients_or_current(name, recipients)?)?;
+        let ciphertext = encrypt_contents(self.backend(), &keys, serialize_stored_env(env))?;
+        self.write_version(name, &file, &ciphertext, action)?;
+        if let Some(recipients) = recipients {
+            write_env_recipients(&self.config_dir, name, recipients)?;
+        }
+        clear_reencryption_flag(&self.config_dir, name)
+    }
+
+    /// Encrypt `env` into environment `name` with `passphrase` instead of recipients
+    pub fn save_with_passphrase(
+        &self,
+        name: &str,
+        env: &BTreeMap<String, String>,
+        passphrase: &SecretString,
+        action: &str,
+    ) -> Result<()> {
+        let _lock = self.lock(true)?;
+        check_env_path(&self.envs_dir(), name)?;
+        let file = self.env_file(name)?;
+        fs::create_dir_all(file.parent().unwrap())
+            .context("Failed to create environment folder")?;
+        let ciphertext = encrypt_with_passphrase(passphrase, serialize_stored_env(env).as_bytes())?;
+        self.write_version(name, &file, &ciphertext, action)?;
+        self.passphrases
+            .borrow_mut()
+            .insert(name.to_string(), passphrase.clone());
+        remove_env_recipients(&self.config_dir, name)?;
+        clear_reencryption_flag(&self.config_dir, name)
+    }
+
+    /// Encrypt environment `name` again, for `recipients` or the ones it currently has, or
+    /// with its passphrase if it is passphrase protected
+    pub fn reencrypt(&self, name: &str, recipients: Option<&[String]>) -> Result<()> {
+        let _lock = self.lock(true)?;
+        let file = self.env_file(name)?;
+        if !file.is_file() {
+            return Err(Error::EnvNotFound(name.to_string()));
+        }
+        let contents = String::from_utf8(self.decrypt(name, &file)?)
+            .map_err(|_| Error::InvalidInput(format!("Environment {} is not valid UTF-8", name)))?;
+        if self.is_passphrase_protected(name)? {
+            let passphrase = self.passphrase(name)?;
+            let ciphertext = encrypt_with_passphrase(&passphrase, contents.as_bytes())?;
+            return self.write_version(name, &file, &ciphertext, "reencrypt");
+        }
+        let keys = self.recipient_keys(&self.recipients_or_current(name, recipients)?)?;
+        let ciphertext = encrypt_contents(self.backend(), &keys, contents)?;
+        self.write_version(name, &file, &ciphertext, "reencrypt")?;
+        if let Some(recipients) = recipients {
+            write_env_recipients(&self.config_dir, name, recipients)?;
+        }
+        clear_reencryption_flag(&self.config_dir, name)
+    }
+
+    /// Replace `file`, the file of environment `name`, with `ciphertext`, recording it in
+    /// the history first so a failure leaves the environment as it was
+    fn write_version(
+        &self,
+        name: &str,
+        file: &Path,
+        ciphertext: &[u8],
+        action: &str,
+    ) -> Result<()> {
+        snapshot_before_change(&self.config_dir, file, name)?;
+        record_version(&self.config_dir, name, ciphertext, action)?;
+        write_atomic(file, ciphertext)
+    }
+
+    fn recipients_or_current(
+        &self,
+        name: &str,
+        recipients: Option<&[String]>,
+    ) -> Result<Vec<String>> {
+        match recipients {
+            Some(recipients) => Ok(recipients.to_vec()),
+            None => self.recipients(name),
+        }
+    }
+
+    /// The public keys `recipients` refer to, expanding groups and names
+    pub fn recipient_keys(&self, recipients: &[String]) -> Result<Vec<String>> {
+        resolve_recipient_keys(
+            self.backend(),
+            &self.config_dir,
+            self.global_recipients_file().as_deref(),
+            recipients,
+        )
+    }
+
+    /// Delete environment `name`, keeping its history
+    pub fn delete(&self, name: &str) -> Result<()> {
+        let _lock = self.lock(true)?;
+        let file = self.env_file(name)?;
+        if !file.is_file() {
+            return Err(Error::EnvNotFound(name.to_string()));
+        }
+        snapshot_before_change(&self.config_dir, &file, name)?;
+        record_deletion(&self.config_dir, name)?;
+        fs::remove_file(&file).context("Failed to delete environment file")?;
+        remove_empty_parents(&self.envs_dir(), &file)?;
+        remove_env_recipients(&self.config_dir, name)?;
+        remove_env_identities(&self.config_dir, name)?;
+        clear_reencryption_flag(&self.config_dir, name)
+    }
+}
+
+/// Parse decrypted contents of environment `name`
+pub fn parse_env_contents(name: &str, contents: Vec<u8>) -> Result<BTreeMap<String, String>> {
+    let contents = String::from_utf8(contents)
+        .map_err(|_| Error::InvalidInput(format!("Environment {} is not valid UTF-8", name)))?;
+    parse_stored_env(&contents)
+        .map_err(|error| Error::parse(format!("environment {}", name), error))
+}